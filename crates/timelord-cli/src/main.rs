@@ -1,7 +1,7 @@
 #![doc = include_str!("../README.md")]
 
 use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -20,18 +20,330 @@ enum TlCommand {
         source_dir: Utf8PathBuf,
 
         /// The cache directory to store the timestamp database, should be persistent across CI builds.
-        /// The file will be written in the cache directory as `timelord.db`.
+        /// The file will be written in the cache directory as `<cache-name>.db`.
+        /// With `--cache-url`, a local copy of downloaded caches is kept there instead.
+        #[arg(long, required_unless_present_any = ["cache_url", "s3_bucket"])]
+        cache_dir: Option<Utf8PathBuf>,
+
+        /// Fetch and upload the cache from an HTTP cache supporting GET and PUT, like
+        /// `https://cache/{repo}/{branch}/{name}.db`. `{name}` is the cache name, other placeholders come from
+        /// `--url-var`.
         #[arg(long)]
-        cache_dir: Utf8PathBuf,
+        cache_url: Option<String>,
+
+        /// A value for a `--cache-url` placeholder, like `branch=main`; can be repeated.
+        #[arg(long, value_parser = parse_url_var)]
+        url_var: Vec<(String, String)>,
+
+        /// Keep the cache in this S3 bucket instead of the cache directory, signing requests with the
+        /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN` environment variables.
+        #[arg(long, conflicts_with_all = ["cache_dir", "cache_url"])]
+        s3_bucket: Option<String>,
+
+        /// Prefix for object keys in the S3 bucket, like `timelord/main/` to key caches by repository and branch.
+        #[arg(long, requires = "s3_bucket", default_value = "")]
+        s3_prefix: String,
+
+        /// The S3 bucket's region.
+        #[arg(
+            long,
+            requires = "s3_bucket",
+            env = "AWS_REGION",
+            default_value = "us-east-1"
+        )]
+        s3_region: String,
+
+        /// Another S3-compatible endpoint, like `http://localhost:9000` for MinIO.
+        #[arg(long, requires = "s3_bucket")]
+        s3_endpoint: Option<String>,
+
+        /// Name of the database in the cache directory, so several workspaces or profiles can share it.
+        #[arg(long, default_value = timelord::DEFAULT_CACHE_NAME, value_parser = parse_cache_name)]
+        cache_name: String,
+
+        /// Name of a cache in the cache directory to restore timestamps from instead of the one being written; can be
+        /// repeated, the first valid one wins. For PR builds, pass the branch's own cache first, then the main branch's.
+        #[arg(long, value_parser = parse_cache_name)]
+        read_from: Vec<String>,
+
+        /// Restore timestamps but never write the cache, e.g. for fork PRs or release builds.
+        #[arg(long)]
+        read_only: bool,
+
+        /// What mtime to give new and dirty files, which can't be restored from the cache.
+        #[arg(long, value_enum, default_value_t = Fallback::Checkout)]
+        fallback: Fallback,
+
+        /// Clamp every timestamp to the `SOURCE_DATE_EPOCH` environment variable, for reproducible builds.
+        /// Files that can't be restored from the cache get that timestamp.
+        #[arg(long)]
+        reproducible: bool,
+
+        /// Touch every new and dirty file to a single time, strictly greater than every restored timestamp,
+        /// so dirty files always look newer than fresh ones even if clocks disagree between runners.
+        #[arg(long)]
+        touch_dirty: bool,
+
+        /// What to do with cached timestamps that are ahead of the local clock.
+        #[arg(long, value_enum, default_value_t = ClockSkew::Warn)]
+        clock_skew: ClockSkew,
+
+        /// Also restore access times (atime) of fresh files, for tools that rely on them.
+        #[arg(long)]
+        preserve_atime: bool,
+
+        /// Keep cache entries for files missing from the source directory (e.g. outside a sparse checkout),
+        /// as long as they were last seen within this long, like `14d`.
+        #[arg(long, value_parser = humantime::parse_duration)]
+        retain_missing: Option<std::time::Duration>,
+
+        /// Restore timestamps of new files whose contents exactly match a single file that disappeared.
+        #[arg(long)]
+        detect_renames: bool,
+
+        /// Write every file's status, dirty reason, hash, size and timestamps to this file.
+        #[arg(long)]
+        report: Option<Utf8PathBuf>,
+
+        /// The format of the `--report` file.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Json, requires = "report")]
+        report_format: ReportFormat,
+
+        /// Map dirty, new and deleted files to the Cargo packages owning them, using `cargo metadata`, and write
+        /// those packages and their reverse dependencies to this file as JSON.
+        #[arg(long)]
+        affected_packages: Option<Utf8PathBuf>,
+
+        /// Append a summary of the sync to `$GITHUB_STEP_SUMMARY`, and emit cache problems and failures to set
+        /// mtimes as warning annotations. On by default on GitHub Actions, `--github-actions=false` turns it off.
+        #[arg(
+            long,
+            env = "GITHUB_ACTIONS",
+            value_name = "BOOL",
+            num_args = 0..=1,
+            require_equals = true,
+            default_value_t = false,
+            default_missing_value = "true"
+        )]
+        github_actions: bool,
     },
-    /// Display information about the cache
+    /// List every cache database in the cache directory
     CacheInfo {
-        /// The cache directory containing the timelord.db file
+        /// The cache directory containing the databases
+        #[arg(long)]
+        cache_dir: Utf8PathBuf,
+    },
+    /// Prune cache entries that haven't been seen in a while
+    Gc {
+        /// The cache directory containing the database
         #[arg(long)]
         cache_dir: Utf8PathBuf,
+
+        /// Name of the database in the cache directory.
+        #[arg(long, default_value = timelord::DEFAULT_CACHE_NAME, value_parser = parse_cache_name)]
+        cache_name: String,
+
+        /// Prune entries last seen longer ago than this, like `30d`.
+        #[arg(long, value_parser = humantime::parse_duration)]
+        max_age: Option<std::time::Duration>,
+
+        /// Prune entries not seen during the last N syncs, at least 1.
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        max_runs: Option<u64>,
+    },
+    /// Merge caches made by parallel CI shards into one
+    Merge {
+        /// The cache databases to merge, like `shard-1/timelord.db`
+        #[arg(required = true)]
+        inputs: Vec<Utf8PathBuf>,
+
+        /// Where to write the merged database, like `cache/timelord.db`
+        #[arg(short, long)]
+        output: Utf8PathBuf,
+
+        /// Which entry to keep when caches disagree about a file's contents.
+        #[arg(long, value_enum, default_value_t = Merge::NewestCrawl)]
+        policy: Merge,
+    },
+    /// Print a cache key for CI cache actions, then fallback restore keys, one per line
+    Key {
+        /// The source directory, where files are looked up and the repository, branch and toolchain are detected
+        #[arg(long, default_value = ".")]
+        source_dir: Utf8PathBuf,
+
+        /// A file whose contents go into the key, relative to the source directory; can be repeated.
+        /// Defaults to `Cargo.lock`, if there's one.
+        #[arg(long)]
+        file: Vec<Utf8PathBuf>,
+
+        /// The repository, like `owner/name`. Detected from CI variables or the `origin` remote by default.
+        #[arg(long)]
+        repo: Option<String>,
+
+        /// The branch. Detected from CI variables or git by default.
+        #[arg(long)]
+        branch: Option<String>,
+
+        /// The toolchain. Detected from `rustc --version` by default.
+        #[arg(long)]
+        toolchain: Option<String>,
+
+        /// What every key starts with.
+        #[arg(long, default_value = "timelord")]
+        prefix: String,
+
+        /// How to print the keys.
+        #[arg(long, value_enum, default_value_t = KeyFormat::Plain)]
+        format: KeyFormat,
     },
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ReportFormat {
+    /// An array with one object per file
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// A header, then one row per file
+    Csv,
+}
+
+impl From<ReportFormat> for timelord::ReportFormat {
+    fn from(format: ReportFormat) -> Self {
+        match format {
+            ReportFormat::Json => timelord::ReportFormat::Json,
+            ReportFormat::Ndjson => timelord::ReportFormat::Ndjson,
+            ReportFormat::Csv => timelord::ReportFormat::Csv,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum KeyFormat {
+    /// The key, then restore keys, one per line
+    Plain,
+    /// `key` and `restore-keys` outputs, to append to `$GITHUB_OUTPUT`
+    Github,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Fallback {
+    /// Keep the mtime from the checkout
+    Checkout,
+    /// Give new files the time of the last git commit that touched them
+    GitCommitTime,
+}
+
+impl From<Fallback> for timelord::FallbackPolicy {
+    fn from(fallback: Fallback) -> Self {
+        match fallback {
+            Fallback::Checkout => timelord::FallbackPolicy::Checkout,
+            Fallback::GitCommitTime => timelord::FallbackPolicy::GitCommitTime,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ClockSkew {
+    /// Restore them anyway, with a warning
+    Warn,
+    /// Restore them as the current time
+    Clamp,
+    /// Don't restore them
+    Skip,
+}
+
+impl From<ClockSkew> for timelord::ClockSkewPolicy {
+    fn from(clock_skew: ClockSkew) -> Self {
+        match clock_skew {
+            ClockSkew::Warn => timelord::ClockSkewPolicy::Warn,
+            ClockSkew::Clamp => timelord::ClockSkewPolicy::Clamp,
+            ClockSkew::Skip => timelord::ClockSkewPolicy::Skip,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Merge {
+    /// Keep the entry from the most recently crawled cache
+    NewestCrawl,
+    /// Keep the entry with the oldest timestamp
+    OldestTimestamp,
+}
+
+impl From<Merge> for timelord::MergePolicy {
+    fn from(merge: Merge) -> Self {
+        match merge {
+            Merge::NewestCrawl => timelord::MergePolicy::NewestCrawl,
+            Merge::OldestTimestamp => timelord::MergePolicy::OldestTimestamp,
+        }
+    }
+}
+
+fn parse_url_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+        None => Err("must look like `key=value`".to_owned()),
+    }
+}
+
+/// Where `timelord sync` keeps caches
+struct StoreArgs {
+    cache_dir: Option<Utf8PathBuf>,
+    cache_url: Option<String>,
+    url_vars: Vec<(String, String)>,
+    s3_bucket: Option<String>,
+    s3_prefix: String,
+    s3_region: String,
+    s3_endpoint: Option<String>,
+}
+
+fn cache_store(args: StoreArgs) -> Box<dyn timelord::CacheStore> {
+    let StoreArgs {
+        cache_dir,
+        cache_url,
+        url_vars,
+        s3_bucket,
+        s3_prefix,
+        s3_region,
+        s3_endpoint,
+    } = args;
+    if let Some(bucket) = s3_bucket {
+        let Some(credentials) = timelord::S3Credentials::from_env() else {
+            eprintln!("--s3-bucket requires AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to be set");
+            std::process::exit(1);
+        };
+        let store = timelord::S3Store::new(bucket, s3_region, credentials).with_prefix(s3_prefix);
+        return match s3_endpoint {
+            Some(endpoint) => Box::new(store.with_endpoint(endpoint)),
+            None => Box::new(store),
+        };
+    }
+    let Some(cache_url) = cache_url else {
+        return Box::new(timelord::FsStore::new(
+            cache_dir.expect("clap requires --cache-dir without --cache-url"),
+        ));
+    };
+    let store = match timelord::HttpStore::new(cache_url, url_vars.into_iter().collect()) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("invalid --cache-url: {e}");
+            std::process::exit(1);
+        }
+    };
+    match cache_dir {
+        Some(cache_dir) => Box::new(store.with_mirror(cache_dir)),
+        None => Box::new(store),
+    }
+}
+
+fn parse_cache_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err("must be a plain file name, like `release`".to_owned());
+    }
+    Ok(name.to_owned())
+}
+
 fn main() {
     if std::env::var("RUST_LOG").is_err() {
         unsafe { std::env::set_var("RUST_LOG", "info") };
@@ -47,11 +359,154 @@ fn main_with_args(args: Args) {
         TlCommand::Sync {
             source_dir,
             cache_dir,
+            cache_url,
+            url_var,
+            s3_bucket,
+            s3_prefix,
+            s3_region,
+            s3_endpoint,
+            cache_name,
+            read_from,
+            read_only,
+            fallback,
+            reproducible,
+            touch_dirty,
+            clock_skew,
+            preserve_atime,
+            retain_missing,
+            detect_renames,
+            report: report_path,
+            report_format,
+            affected_packages,
+            github_actions,
         } => {
-            timelord::sync(source_dir, cache_dir);
+            let source_date_epoch = if reproducible {
+                match timelord::source_date_epoch() {
+                    Some(epoch) => Some(epoch),
+                    None => {
+                        eprintln!(
+                            "--reproducible requires SOURCE_DATE_EPOCH to be set to a unix timestamp"
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            let options = timelord::SyncOptions {
+                fallback: fallback.into(),
+                source_date_epoch,
+                touch_dirty,
+                clock_skew: clock_skew.into(),
+                preserve_atime,
+                retain_missing,
+                detect_renames,
+                cache_name: Some(cache_name),
+                read_from,
+                read_only,
+            };
+            let store = cache_store(StoreArgs {
+                cache_dir,
+                cache_url,
+                url_vars: url_var,
+                s3_bucket,
+                s3_prefix,
+                s3_region,
+                s3_endpoint,
+            });
+            let report = timelord::sync_with_store(source_dir.clone(), &*store, &options);
+            if github_actions {
+                timelord::report_to_github_actions(&report, &*store);
+            }
+            if let Some(report_path) = report_path
+                && let Err(e) = timelord::write_report(&report, &report_path, report_format.into())
+            {
+                eprintln!("Failed to write report to {report_path}: {e}");
+                std::process::exit(1);
+            }
+            if let Some(affected_path) = affected_packages {
+                let affected = match timelord::affected_packages(&source_dir, &report) {
+                    Ok(affected) => affected,
+                    Err(e) => {
+                        eprintln!("Failed to map dirty files to packages: {e}");
+                        std::process::exit(1);
+                    }
+                };
+                if let Err(e) = affected.write_json(&affected_path) {
+                    eprintln!("Failed to write affected packages to {affected_path}: {e}");
+                    std::process::exit(1);
+                }
+            }
+            if report.save_error.is_some() {
+                std::process::exit(1);
+            }
         }
         TlCommand::CacheInfo { cache_dir } => {
             timelord::cache_info(cache_dir);
         }
+        TlCommand::Gc {
+            cache_dir,
+            cache_name,
+            max_age,
+            max_runs,
+        } => {
+            if max_age.is_none() && max_runs.is_none() {
+                eprintln!("gc needs --max-age, --max-runs, or both");
+                std::process::exit(1);
+            }
+            if timelord::gc(cache_dir, &cache_name, max_age, max_runs).is_none() {
+                std::process::exit(1);
+            }
+        }
+        TlCommand::Key {
+            source_dir,
+            file,
+            repo,
+            branch,
+            toolchain,
+            prefix,
+            format,
+        } => {
+            let options = timelord::KeyOptions {
+                prefix: Some(prefix),
+                repo,
+                branch,
+                toolchain,
+                files: file,
+            };
+            let key = match timelord::cache_key(&source_dir, &options) {
+                Ok(key) => key,
+                Err(e) => {
+                    eprintln!("Failed to compute a cache key: {e}");
+                    std::process::exit(1);
+                }
+            };
+            // The keys are this command's output, meant to be captured
+            match format {
+                KeyFormat::Plain => {
+                    println!("{}", key.key);
+                    for restore_key in &key.restore_keys {
+                        println!("{restore_key}");
+                    }
+                }
+                KeyFormat::Github => {
+                    println!("key={}", key.key);
+                    println!("restore-keys<<EOF");
+                    for restore_key in &key.restore_keys {
+                        println!("{restore_key}");
+                    }
+                    println!("EOF");
+                }
+            }
+        }
+        TlCommand::Merge {
+            inputs,
+            output,
+            policy,
+        } => {
+            if timelord::merge(&inputs, &output, policy.into()).is_none() {
+                std::process::exit(1);
+            }
+        }
     }
 }
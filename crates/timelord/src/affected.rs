@@ -0,0 +1,168 @@
+//! Which Cargo packages a sync's dirty files belong to, so CI can limit what
+//! it tests to the packages that will rebuild
+
+use crate::{EntryKind, RelativePath, SyncReport};
+use camino::{Utf8Path, Utf8PathBuf};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::Command;
+
+/// The workspace packages touched by a sync, by name, sorted
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AffectedPackages {
+    /// Packages that own a dirty, new or deleted file
+    pub direct: Vec<String>,
+    /// Packages depending on those, directly or not, that aren't in `direct`.
+    /// Dev and build dependencies count.
+    pub dependents: Vec<String>,
+    /// Dirty, new or deleted files outside every package, like a virtual
+    /// workspace's `Cargo.toml`. CI may want to test everything if there
+    /// are any.
+    pub unowned: Vec<RelativePath>,
+}
+
+impl AffectedPackages {
+    /// Every affected package, direct or dependent, sorted
+    pub fn all(&self) -> Vec<String> {
+        let mut all: Vec<String> = self
+            .direct
+            .iter()
+            .chain(&self.dependents)
+            .cloned()
+            .collect();
+        all.sort();
+        all
+    }
+
+    /// Writes the affected packages to `path` as a JSON object with `direct`,
+    /// `dependents` and `unowned` arrays
+    pub fn write_json(&self, path: &Utf8Path) -> std::io::Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        std::fs::write(path, json)
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    manifest_path: Utf8PathBuf,
+    dependencies: Vec<Dependency>,
+}
+
+#[derive(Deserialize)]
+struct Dependency {
+    name: String,
+    /// Set for path dependencies, which is what workspace members use
+    path: Option<Utf8PathBuf>,
+}
+
+/// Maps the dirty, new and deleted files of `report` to the workspace packages
+/// owning them, according to `cargo metadata` run in `source_dir`. A file
+/// belongs to the package with the closest manifest directory above it.
+pub fn affected_packages(
+    source_dir: &Utf8Path,
+    report: &SyncReport,
+) -> std::io::Result<AffectedPackages> {
+    let source_dir = source_dir.canonicalize_utf8()?;
+    let packages = workspace_packages(&source_dir)?;
+
+    // Deepest manifest directories first, so nested packages win
+    let mut dirs: Vec<(Utf8PathBuf, &str)> = packages
+        .iter()
+        .filter_map(|package| {
+            let dir = package.manifest_path.parent()?;
+            let dir = dir.canonicalize_utf8().unwrap_or_else(|_| dir.to_owned());
+            Some((dir, package.name.as_str()))
+        })
+        .collect();
+    dirs.sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+
+    let changed = report
+        .files
+        .iter()
+        // A directory's changed children are reported on their own
+        .filter(|file| file.dirty_reason.is_some() && file.kind != EntryKind::Directory)
+        .map(|file| &file.path)
+        .chain(report.deleted.iter().map(|entry| &entry.path));
+    let mut direct = BTreeSet::new();
+    let mut unowned = Vec::new();
+    for path in changed {
+        let absolute = source_dir.join(&path.0);
+        match dirs.iter().find(|(dir, _)| absolute.starts_with(dir)) {
+            Some((_, name)) => {
+                direct.insert(name.to_string());
+            }
+            None => unowned.push(path.clone()),
+        }
+    }
+    unowned.sort();
+
+    // Reverse dependency edges between workspace packages
+    let names: BTreeSet<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    let mut dependents_of: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for package in &packages {
+        for dependency in &package.dependencies {
+            if dependency.path.is_some() && names.contains(dependency.name.as_str()) {
+                dependents_of
+                    .entry(dependency.name.as_str())
+                    .or_default()
+                    .push(package.name.as_str());
+            }
+        }
+    }
+    let mut dependents = BTreeSet::new();
+    let mut queue: Vec<&str> = direct.iter().map(|name| name.as_str()).collect();
+    while let Some(name) = queue.pop() {
+        for &dependent in dependents_of.get(name).into_iter().flatten() {
+            if !direct.contains(dependent) && dependents.insert(dependent.to_owned()) {
+                queue.push(dependent);
+            }
+        }
+    }
+
+    let affected = AffectedPackages {
+        direct: direct.into_iter().collect(),
+        dependents: dependents.into_iter().collect(),
+        unowned,
+    };
+    if affected.direct.is_empty() {
+        info!("📦 No package has dirty files");
+    } else {
+        info!("📦 Dirty packages: {}", affected.direct.join(", "));
+        if !affected.dependents.is_empty() {
+            info!("📦 Depending on them: {}", affected.dependents.join(", "));
+        }
+    }
+    if !affected.unowned.is_empty() {
+        info!(
+            "📦 {} dirty files are outside every package",
+            affected.unowned.len()
+        );
+    }
+    Ok(affected)
+}
+
+/// Lists the workspace's packages without resolving dependencies, which
+/// needs neither the network nor a lockfile
+fn workspace_packages(source_dir: &Utf8Path) -> std::io::Result<Vec<Package>> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let output = Command::new(cargo)
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .current_dir(source_dir)
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let metadata: Metadata = serde_json::from_slice(&output.stdout)?;
+    Ok(metadata.packages)
+}
@@ -0,0 +1,150 @@
+//! Helpers that shell out to `git` to recover timestamps from history
+
+use crate::RelativePath;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+/// Finds the time of the last commit that touched each of the `wanted` paths.
+///
+/// Paths are relative to `dir`, which may be a subdirectory of the repository.
+/// Files with uncommitted changes are left out, since their commit time doesn't
+/// describe their current contents. History is walked newest-first and the walk
+/// stops as soon as every wanted path has been seen.
+pub(crate) fn last_commit_times(
+    dir: &Utf8Path,
+    wanted: &HashSet<RelativePath>,
+) -> std::io::Result<HashMap<RelativePath, SystemTime>> {
+    let mut times = HashMap::new();
+    if wanted.is_empty() {
+        return Ok(times);
+    }
+
+    let mut remaining = wanted.clone();
+    for path in uncommitted_paths(dir)? {
+        remaining.remove(&path);
+    }
+
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args([
+            "log",
+            "--format=format:%ct",
+            "--name-only",
+            "--no-renames",
+            "--relative",
+            "-z",
+            "--",
+            ".",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Output looks like `<ct>\n<name>\0<name>\0\0<ct>\n<name>\0...`
+    let mut reader = BufReader::new(child.stdout.take().unwrap());
+    let mut commit_time = None;
+    let mut token = Vec::new();
+    while !remaining.is_empty() {
+        token.clear();
+        if reader.read_until(b'\0', &mut token)? == 0 {
+            break;
+        }
+        if token.last() == Some(&b'\0') {
+            token.pop();
+        }
+        let token = String::from_utf8_lossy(&token);
+        let name = match token.split_once('\n') {
+            Some((ct, name)) => {
+                commit_time = ct
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+                name
+            }
+            None => &token,
+        };
+        if name.is_empty() {
+            continue;
+        }
+        let path = RelativePath(Utf8PathBuf::from(name));
+        if let Some(commit_time) = commit_time
+            && remaining.remove(&path)
+        {
+            times.insert(path, commit_time);
+        }
+    }
+
+    // We may have stopped early, git doesn't need to walk the rest of history
+    let _ = child.kill();
+    let _ = child.wait();
+
+    Ok(times)
+}
+
+/// Lists paths (relative to `dir`) that differ from `HEAD`, staged or not.
+fn uncommitted_paths(dir: &Utf8Path) -> std::io::Result<Vec<RelativePath>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args([
+            "diff",
+            "--name-only",
+            "--no-renames",
+            "--relative",
+            "-z",
+            "HEAD",
+        ])
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "git diff exited with {}",
+            output.status
+        )));
+    }
+    Ok(output
+        .stdout
+        .split(|&b| b == b'\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| RelativePath(Utf8PathBuf::from(String::from_utf8_lossy(name).as_ref())))
+        .collect())
+}
+
+/// The branch checked out in `dir`, `None` if `HEAD` is detached
+pub(crate) fn current_branch(dir: &Utf8Path) -> Option<String> {
+    let branch = git_output(dir, &["rev-parse", "--abbrev-ref", "HEAD"])?;
+    (branch != "HEAD").then_some(branch)
+}
+
+/// Whether the repository `dir` is in is a shallow clone, whose oldest commit
+/// looks like it added every file
+pub(crate) fn is_shallow(dir: &Utf8Path) -> bool {
+    git_output(dir, &["rev-parse", "--is-shallow-repository"]).as_deref() == Some("true")
+}
+
+/// The URL of the `origin` remote of the repository `dir` is in
+pub(crate) fn origin_url(dir: &Utf8Path) -> Option<String> {
+    git_output(dir, &["remote", "get-url", "origin"])
+}
+
+/// Runs git in `dir` and returns its trimmed output, `None` if it failed
+fn git_output(dir: &Utf8Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_owned()).filter(|output| !output.is_empty())
+}
@@ -0,0 +1,152 @@
+//! GitHub Actions integration: a job summary of each sync, and warnings as
+//! workflow commands so they show up on pull requests
+
+use crate::{DirtyReason, EntryKind, SyncReport};
+use log::*;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+
+/// How many of the directories with the most dirty files the summary lists
+const TOP_DIRTY_DIRECTORIES: usize = 5;
+
+/// Reports a sync to GitHub Actions: caches that couldn't be restored from
+/// or saved, and mtimes that couldn't be set, become warnings on the pull
+/// request, and [`step_summary`] is appended to `$GITHUB_STEP_SUMMARY` if
+/// it's set. `store` is where the sync read and wrote caches.
+pub fn report_to_github_actions(report: &SyncReport, store: &dyn std::fmt::Display) {
+    for problem in &report.cache_problems {
+        warning(problem);
+    }
+    if let Some(e) = &report.save_error {
+        warning(&format!("Failed to save the cache to {}: {}", store, e));
+    }
+    let failures = report.failures().count();
+    if let Some(failure) = report.failures().next() {
+        warning(&format!(
+            "Failed to set the mtime of {} files, like {}: {}",
+            failures,
+            failure.path,
+            failure.error.as_ref().unwrap()
+        ));
+    }
+    write_step_summary(report, store);
+}
+
+/// Emits a `::warning::` workflow command, which the runner picks up from
+/// either output stream
+fn warning(message: &str) {
+    eprintln!("::warning title=timelord::{}", escape_command(message));
+}
+
+fn escape_command(message: &str) -> String {
+    message
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Appends [`step_summary`] to the file in `GITHUB_STEP_SUMMARY`, if it's set
+fn write_step_summary(report: &SyncReport, store: &dyn std::fmt::Display) {
+    let Some(path) = std::env::var_os("GITHUB_STEP_SUMMARY").filter(|path| !path.is_empty()) else {
+        return;
+    };
+    let summary = step_summary(report, store);
+    let result = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(summary.as_bytes()));
+    if let Err(e) = result {
+        warn!("⚠️ Failed to write the job summary: {}", e);
+    }
+}
+
+/// A Markdown summary of a sync: how many files were fresh, dirty, new and
+/// deleted, which cache timestamps came from, and the directories with the
+/// most dirty files. Directories aren't counted.
+pub(crate) fn step_summary(report: &SyncReport, store: &dyn std::fmt::Display) -> String {
+    let (mut fresh, mut dirty, mut new) = (0, 0, 0);
+    let mut dirty_directories: BTreeMap<String, usize> = BTreeMap::new();
+    for file in &report.files {
+        if file.kind == EntryKind::Directory {
+            continue;
+        }
+        match file.dirty_reason {
+            None => fresh += 1,
+            Some(reason) => {
+                if reason == DirtyReason::New {
+                    new += 1;
+                } else {
+                    dirty += 1;
+                }
+                let directory = file.path.0.parent().map_or("", |dir| dir.as_str());
+                *dirty_directories.entry(directory.to_owned()).or_default() += 1;
+            }
+        }
+    }
+
+    let mut out = String::new();
+    let _ = writeln!(out, "### ⏱️ timelord\n");
+    let _ = writeln!(out, "| Fresh | Dirty | New | Deleted |");
+    let _ = writeln!(out, "|---:|---:|---:|---:|");
+    let _ = writeln!(
+        out,
+        "| {} | {} | {} | {} |\n",
+        fresh,
+        dirty,
+        new,
+        report
+            .deleted
+            .iter()
+            .filter(|entry| entry.kind != EntryKind::Directory)
+            .count()
+    );
+
+    match (
+        &report.cache_source,
+        report.cache_crawl_time,
+        &report.cache_hostname,
+    ) {
+        (Some(name), Some(crawl_time), Some(hostname)) => {
+            // Sub-second precision is noise here
+            let age = match std::time::SystemTime::now().duration_since(crawl_time) {
+                Ok(age) => format!(
+                    "{} ago",
+                    humantime::format_duration(std::time::Duration::from_secs(age.as_secs()))
+                ),
+                Err(_) => "in the future (clock skew?)".to_owned(),
+            };
+            let _ = writeln!(
+                out,
+                "Restored from cache `{}` in `{}`, crawled {} on `{}`.\n",
+                name, store, age, hostname
+            );
+        }
+        _ => {
+            let _ = writeln!(out, "No valid cache in `{}`, every file is new.\n", store);
+        }
+    }
+
+    let failures = report.failures().count();
+    if failures > 0 {
+        let _ = writeln!(out, "⚠️ Failed to set the mtime of {} files.\n", failures);
+    }
+
+    if !dirty_directories.is_empty() {
+        let mut directories: Vec<_> = dirty_directories.into_iter().collect();
+        directories.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let _ = writeln!(out, "| Top dirty directories | Dirty and new files |");
+        let _ = writeln!(out, "|---|---:|");
+        for (directory, count) in directories.into_iter().take(TOP_DIRTY_DIRECTORIES) {
+            let directory = if directory.is_empty() {
+                "."
+            } else {
+                &directory
+            };
+            let _ = writeln!(out, "| `{}` | {} |", directory, count);
+        }
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,266 @@
+//! A [`CacheStore`] for simple GET/PUT remote caches (bazel-remote, nginx with
+//! WebDAV, ...), by shelling out to `curl`
+
+use crate::CacheStore;
+use camino::Utf8PathBuf;
+use log::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Keeps caches on an HTTP server that supports GET and PUT by URL. Requests
+/// are made by the `curl` binary, which must be on the `PATH`.
+#[derive(Debug, Clone)]
+pub struct HttpStore {
+    url_template: String,
+    vars: BTreeMap<String, String>,
+    mirror: Option<Utf8PathBuf>,
+}
+
+impl HttpStore {
+    /// `url_template` may refer to the cache name as `{name}` and to any of
+    /// `vars`, like `https://cache/{repo}/{branch}/{name}.db`. Fails if it
+    /// refers to anything else.
+    pub fn new(
+        url_template: impl Into<String>,
+        vars: BTreeMap<String, String>,
+    ) -> std::io::Result<Self> {
+        let store = HttpStore {
+            url_template: url_template.into(),
+            vars,
+            mirror: None,
+        };
+        store.url(crate::DEFAULT_CACHE_NAME)?;
+        Ok(store)
+    }
+
+    /// Keeps a copy of downloaded caches in `dir`, so caches that didn't
+    /// change on the server aren't downloaded again (using ETag and
+    /// If-None-Match)
+    pub fn with_mirror(mut self, dir: Utf8PathBuf) -> Self {
+        self.mirror = Some(dir);
+        self
+    }
+
+    fn url(&self, name: &str) -> std::io::Result<String> {
+        let mut url = String::new();
+        let mut rest = self.url_template.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let var = &rest[start + 1..start + len];
+            let value = match var {
+                "name" => name,
+                _ => self.vars.get(var).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("no value for {{{var}}} in {}", self.url_template),
+                    )
+                })?,
+            };
+            url.push_str(&rest[..start]);
+            url.push_str(value);
+            rest = &rest[start + len + 1..];
+        }
+        url.push_str(rest);
+        Ok(url)
+    }
+
+    fn mirror_files(&self, name: &str) -> Option<(Utf8PathBuf, Utf8PathBuf)> {
+        let dir = self.mirror.as_ref()?;
+        Some((
+            crate::cache_file(dir, name),
+            dir.join(format!("{name}.etag")),
+        ))
+    }
+
+    /// Remembers what the server has for `name`, failures only cost a
+    /// download next time
+    fn update_mirror(&self, name: &str, contents: &[u8], etag: Option<&str>) {
+        let Some((cache_file, etag_file)) = self.mirror_files(name) else {
+            return;
+        };
+        let result = cache_file
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&cache_file, contents))
+            .and_then(|_| match etag {
+                Some(etag) => fs::write(&etag_file, etag),
+                None => match fs::remove_file(&etag_file) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                },
+            });
+        if let Err(e) = result {
+            debug!("⚠️ Failed to update the local copy of {}: {}", name, e);
+        }
+    }
+}
+
+impl std::fmt::Display for HttpStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.url_template.fmt(f)
+    }
+}
+
+impl CacheStore for HttpStore {
+    fn get(&self, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let url = self.url(name)?;
+        let mirrored = self.mirror_files(name).and_then(|(cache_file, etag_file)| {
+            let etag = fs::read_to_string(etag_file).ok()?;
+            cache_file.exists().then_some((cache_file, etag))
+        });
+
+        let mut args = vec![url.clone()];
+        if let Some((_, etag)) = &mirrored {
+            args.push("--header".to_owned());
+            args.push(format!("If-None-Match: {etag}"));
+        }
+        let response = curl(&args, None)?;
+        match (response.status, mirrored) {
+            (200, _) => {
+                self.update_mirror(name, &response.body, response.etag.as_deref());
+                Ok(Some(response.body))
+            }
+            (304, Some((cache_file, _))) => {
+                debug!("♻️ {} didn't change, using the local copy", url);
+                fs::read(cache_file).map(Some)
+            }
+            (404, _) => Ok(None),
+            (status, _) => Err(http_error("GET", &url, status)),
+        }
+    }
+
+    fn put(&self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        let url = self.url(name)?;
+        let args = [
+            "--request",
+            "PUT",
+            "--data-binary",
+            "@-",
+            "--header",
+            "Content-Type: application/octet-stream",
+            // Don't wait for a 100 Continue some servers never send
+            "--header",
+            "Expect:",
+            &url,
+        ]
+        .map(String::from);
+        let response = curl(&args, Some(contents))?;
+        if !(200..300).contains(&response.status) {
+            return Err(http_error("PUT", &url, response.status));
+        }
+        self.update_mirror(name, contents, response.etag.as_deref());
+        Ok(())
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "HTTP caches can't be listed",
+        ))
+    }
+}
+
+pub(crate) fn http_error(method: &str, url: &str, status: u16) -> std::io::Error {
+    let kind = match status {
+        401 | 403 => std::io::ErrorKind::PermissionDenied,
+        _ => std::io::ErrorKind::Other,
+    };
+    std::io::Error::new(kind, format!("{method} {url} returned HTTP {status}"))
+}
+
+/// How long to wait for a connection. A sync waits on the cache while holding
+/// its lock, so an unreachable server must not block it for long.
+const CONNECT_TIMEOUT_SECS: u32 = 10;
+
+/// How long a whole request may take, large caches included
+const MAX_TIME_SECS: u32 = 300;
+
+/// How many times curl retries timeouts, 408, 429 and 5xx responses
+const RETRIES: u32 = 2;
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) etag: Option<String>,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Runs curl with `args`, sending `stdin` if any. The status comes from
+/// `--write-out` and the headers from `--dump-header`, so neither a proxy's
+/// `CONNECT` response nor redirects can be mistaken for the real response.
+pub(crate) fn curl(args: &[String], stdin: Option<&[u8]>) -> std::io::Result<Response> {
+    // The body goes to a file too, which curl truncates before retrying
+    let headers = tempfile::Builder::new()
+        .prefix("timelord-headers-")
+        .tempfile()?;
+    let body = tempfile::Builder::new()
+        .prefix("timelord-body-")
+        .tempfile()?;
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--location"])
+        .args(["--connect-timeout", &CONNECT_TIMEOUT_SECS.to_string()])
+        .args(["--max-time", &MAX_TIME_SECS.to_string()])
+        .args(["--retry", &RETRIES.to_string()])
+        .arg("--dump-header")
+        .arg(headers.path())
+        .arg("--output")
+        .arg(body.path())
+        .args(["--write-out", "%{http_code}"])
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(stdin) = stdin {
+        // curl reads all of it (a request body or a config) before sending
+        // the request
+        child.stdin.take().unwrap().write_all(stdin)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "curl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let status = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<u16>()
+        .ok()
+        .filter(|&status| status != 0)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "curl didn't get an HTTP response",
+            )
+        })?;
+    Ok(Response {
+        status,
+        etag: final_etag(&fs::read(headers.path())?),
+        body: fs::read(body.path())?,
+    })
+}
+
+/// Finds the ETag in curl's `--dump-header` output, which has one block of
+/// headers per response: a proxy's `CONNECT` and redirects come first, the
+/// final response last
+pub(crate) fn final_etag(headers: &[u8]) -> Option<String> {
+    let headers = String::from_utf8_lossy(headers);
+    let last = headers
+        .split("\r\n\r\n")
+        .filter(|block| !block.trim().is_empty())
+        .last()?;
+    last.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case("etag")
+            .then(|| value.trim().to_owned())
+    })
+}
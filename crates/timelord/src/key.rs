@@ -0,0 +1,142 @@
+//! Recommended keys for CI cache actions, so the right cache gets restored
+
+use camino::{Utf8Path, Utf8PathBuf};
+use std::process::{Command, Stdio};
+
+/// What goes into a [`cache_key`]. Fields left empty are detected from the CI
+/// environment, git and `rustc`.
+#[derive(Debug, Clone, Default)]
+pub struct KeyOptions {
+    /// Starts every key, `timelord` by default
+    pub prefix: Option<String>,
+    /// Like `owner/name`
+    pub repo: Option<String>,
+    pub branch: Option<String>,
+    /// Like `1.85.0-4d91de4e4`
+    pub toolchain: Option<String>,
+    /// Files whose contents go into the key, relative to the source directory.
+    /// Defaults to `Cargo.lock`, if there's one.
+    pub files: Vec<Utf8PathBuf>,
+}
+
+/// A key to save the cache under, and keys to restore it from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKey {
+    pub key: String,
+    /// Prefixes of `key` to fall back on when there's no cache for it, most
+    /// specific first: the same branch first, then any branch
+    pub restore_keys: Vec<String>,
+}
+
+/// Builds a cache key from the repository, toolchain, branch and the hash of
+/// chosen files, in that order. Files are hashed the same way as during a sync.
+pub fn cache_key(source_dir: &Utf8Path, options: &KeyOptions) -> std::io::Result<CacheKey> {
+    let prefix = options.prefix.as_deref().unwrap_or("timelord");
+    let repo = match &options.repo {
+        Some(repo) => repo.clone(),
+        None => detect_repo(source_dir),
+    };
+    let branch = match &options.branch {
+        Some(branch) => branch.clone(),
+        None => detect_branch(source_dir),
+    };
+    let toolchain = match &options.toolchain {
+        Some(toolchain) => toolchain.clone(),
+        None => detect_toolchain(source_dir),
+    };
+
+    let mut files = options.files.clone();
+    if files.is_empty() && source_dir.join("Cargo.lock").exists() {
+        files.push("Cargo.lock".into());
+    }
+    files.sort();
+    files.dedup();
+    let mut hashes = Vec::new();
+    for file in &files {
+        let (hash, _) = crate::hash_file(&source_dir.join(file)).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("failed to hash {}: {}", file, e))
+        })?;
+        hashes.extend_from_slice(file.as_str().as_bytes());
+        hashes.push(b'\0');
+        hashes.extend_from_slice(&hash.0.to_le_bytes());
+    }
+    let files_hash = seahash::hash(&hashes);
+
+    let repo_key = format!("{}-{}-", sanitize(prefix), sanitize(&repo));
+    let toolchain_key = format!("{}{}-", repo_key, sanitize(&toolchain));
+    let branch_key = format!("{}{}-", toolchain_key, sanitize(&branch));
+    Ok(CacheKey {
+        key: format!("{}{:016x}", branch_key, files_hash),
+        restore_keys: vec![branch_key, toolchain_key],
+    })
+}
+
+/// Keeps characters every CI cache accepts in keys, replacing the others with
+/// `_`. That includes `-`, which separates parts: otherwise branch `main`'s
+/// restore key would also match branch `main-2`.
+fn sanitize(part: &str) -> String {
+    part.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn non_empty_env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn detect_repo(source_dir: &Utf8Path) -> String {
+    if let Some(repo) =
+        non_empty_env("GITHUB_REPOSITORY").or_else(|| non_empty_env("CI_PROJECT_PATH"))
+    {
+        return repo;
+    }
+    // `https://host/owner/name.git` or `git@host:owner/name.git`
+    if let Some(url) = crate::git::origin_url(source_dir) {
+        let path = url.trim_end_matches('/').trim_end_matches(".git");
+        let mut parts = path.rsplit(['/', ':']);
+        if let (Some(name), Some(owner)) = (parts.next(), parts.next()) {
+            return format!("{owner}/{name}");
+        }
+    }
+    source_dir
+        .canonicalize_utf8()
+        .ok()
+        .and_then(|dir| dir.file_name().map(|name| name.to_owned()))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+fn detect_branch(source_dir: &Utf8Path) -> String {
+    // Pull requests check out a merge commit, their branch is in GITHUB_HEAD_REF
+    non_empty_env("GITHUB_HEAD_REF")
+        .or_else(|| non_empty_env("GITHUB_REF_NAME"))
+        .or_else(|| non_empty_env("CI_COMMIT_REF_NAME"))
+        .or_else(|| crate::git::current_branch(source_dir))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// The version and commit of the `rustc` the source directory builds with,
+/// which respects `rust-toolchain.toml` under rustup
+fn detect_toolchain(source_dir: &Utf8Path) -> String {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let output = Command::new(rustc)
+        .arg("--version")
+        .current_dir(source_dir)
+        .stderr(Stdio::null())
+        .output();
+    let version = match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        _ => return "unknown".to_owned(),
+    };
+    // `rustc 1.85.0 (4d91de4e4 2025-02-17)`
+    let mut parts = version.split_whitespace().skip(1);
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(commit)) => format!("{}-{}", version, commit.trim_start_matches('(')),
+        (Some(version), None) => version.to_owned(),
+        _ => "unknown".to_owned(),
+    }
+}
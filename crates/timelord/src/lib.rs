@@ -1,6 +1,7 @@
 #![doc = include_str!("../README.md")]
 
 // Always use eprintln! instead of println! for output
+use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use ignore::DirEntry;
 use ignore::WalkBuilder;
@@ -9,15 +10,33 @@ use rayon::iter::IntoParallelRefMutIterator;
 use rayon::iter::ParallelIterator;
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::Read;
-use std::io::Write;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+mod affected;
+mod git;
+mod github;
+mod http;
+mod key;
+mod report;
+mod s3;
+mod store;
+mod times;
+
+pub use affected::{AffectedPackages, affected_packages};
+pub use github::report_to_github_actions;
+pub use http::HttpStore;
+pub use key::{CacheKey, KeyOptions, cache_key};
+pub use report::{ReportFormat, write_report};
+pub use s3::{S3Credentials, S3Store};
+pub use store::{CacheLock, CacheStore, FsStore, MemoryStore};
+
 #[cfg(test)]
 mod tests;
 
@@ -28,7 +47,7 @@ pub struct Workspace {
 }
 
 /// Represents a relative path within the workspace
-#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 #[serde(transparent)]
 pub struct RelativePath(Utf8PathBuf);
 
@@ -39,16 +58,60 @@ impl RelativePath {
     }
 }
 
+impl std::fmt::Display for RelativePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct HashedFile {
     /// The relative path of the file within the workspace
     pub path: RelativePath,
-    /// A hash of the file's contents
+    /// Whether this is a file, a directory or a symlink
+    pub kind: EntryKind,
+    /// A hash of the file's contents, of the names of a directory's children,
+    /// or of a symlink's target
     pub hash: Hash,
-    /// The size of the file in bytes
+    /// The size of the file in bytes, the number of children of a directory,
+    /// or the length of a symlink's target
     pub size: u64,
+    /// Unix permission bits (including the executable bits), always 0 on
+    /// other platforms. Only [`HashedFile::tracked_mode`] is compared.
+    pub mode: u32,
     /// The mtime of the file (last we checked)
     pub timestamp: std::time::SystemTime,
+    /// The atime of the file (last we checked), only restored with
+    /// [`SyncOptions::preserve_atime`]
+    pub accessed: Option<std::time::SystemTime>,
+    /// When a scan last found this file. Older than the cache's crawl time for
+    /// entries carried over by [`SyncOptions::retain_missing`]
+    pub last_seen: std::time::SystemTime,
+    /// The [`Cache::run`] during which a scan last found this file
+    pub last_seen_run: u64,
+}
+
+impl HashedFile {
+    /// The part of `mode` that makes an entry dirty when it changes: the
+    /// executable bits, which are all git tracks. The other bits come from
+    /// the runner's umask, and directory modes from wherever they were made.
+    pub fn tracked_mode(&self) -> u32 {
+        match self.kind {
+            EntryKind::Directory => 0,
+            EntryKind::File | EntryKind::Symlink => self.mode & 0o111,
+        }
+    }
+}
+
+/// What kind of filesystem entry a [`HashedFile`] describes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntryKind {
+    File,
+    /// Directories are fresh as long as the set of their children's names
+    /// doesn't change
+    Directory,
+    /// Symlinks aren't followed, their target is their contents
+    Symlink,
 }
 
 /// The seahash of a file
@@ -62,7 +125,16 @@ impl std::fmt::Display for Hash {
     }
 }
 
-pub const TIMELORD_CACHE_VERSION: u32 = 3;
+pub const TIMELORD_CACHE_VERSION: u32 = 10;
+
+/// Name of the cache database when none is given, stored as `timelord.db`
+pub const DEFAULT_CACHE_NAME: &str = "timelord";
+
+/// Path of the cache database called `cache_name` in `cache_dir`. Several
+/// databases can share a cache directory, e.g. one per repository or profile.
+pub fn cache_file(cache_dir: &Utf8Path, cache_name: &str) -> Utf8PathBuf {
+    cache_dir.join(format!("{cache_name}.db"))
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Cache {
@@ -71,6 +143,15 @@ pub struct Cache {
     pub crawl_time: std::time::SystemTime,
     pub absolute_path: Utf8PathBuf,
     pub hostname: String,
+    /// How many syncs produced this cache, counting this one
+    pub run: u64,
+    /// The name of the cache the sync that made this cache restored
+    /// timestamps from, if any. See [`SyncOptions::read_from`].
+    pub read_from: Option<String>,
+    /// Entries the scan couldn't read, like mode-000 files. Their hash is
+    /// meaningless, so they're always dirty and never saved.
+    #[serde(skip)]
+    pub unreadable: BTreeSet<RelativePath>,
 }
 
 impl Cache {
@@ -81,12 +162,39 @@ impl Cache {
             crawl_time: std::time::SystemTime::now(),
             absolute_path,
             hostname: hostname::get().unwrap().to_string_lossy().into_owned(),
+            run: 0,
+            read_from: None,
+            unreadable: BTreeSet::new(),
         }
     }
+
+    /// Removes entries last seen longer than `max_age` ago, or more than
+    /// `max_runs` runs ago. Returns how many were removed. A `max_runs` of 0
+    /// is ignored, every entry would count as not seen during the last 0 runs.
+    pub fn prune(&mut self, max_age: Option<std::time::Duration>, max_runs: Option<u64>) -> usize {
+        let max_runs = max_runs.filter(|&max_runs| max_runs > 0);
+        let now = std::time::SystemTime::now();
+        let run = self.run;
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| {
+            let too_old = max_age.is_some_and(|max_age| {
+                now.duration_since(entry.last_seen)
+                    .is_ok_and(|age| age > max_age)
+            });
+            let too_many_runs = max_runs
+                .is_some_and(|max_runs| run.saturating_sub(entry.last_seen_run) >= max_runs);
+            !too_old && !too_many_runs
+        });
+        before - self.entries.len()
+    }
 }
 
 pub fn walk_source_dir(workspace: &Workspace) -> Cache {
     let entries = Arc::new(Mutex::new(BTreeMap::new()));
+    let unreadable = Arc::new(Mutex::new(BTreeSet::new()));
+    // How many entries couldn't be read, and the first reason why
+    let errors = Arc::new(Mutex::new((0usize, None::<String>)));
+    let last_seen = std::time::SystemTime::now();
 
     WalkBuilder::new(&workspace.source_dir)
         .standard_filters(false)
@@ -94,38 +202,99 @@ pub fn walk_source_dir(workspace: &Workspace) -> Cache {
         .build_parallel()
         .run(|| {
             let entries_clone = Arc::clone(&entries);
+            let unreadable_clone = Arc::clone(&unreadable);
+            let errors_clone = Arc::clone(&errors);
             let workspace = workspace.clone();
             Box::new(move |entry: Result<DirEntry, ignore::Error>| {
-                let entry = entry.unwrap();
-                if entry.file_type().is_some_and(|ft| ft.is_file()) {
-                    let path =
-                        Utf8PathBuf::try_from(entry.path().to_owned()).unwrap_or_else(|_| {
-                            panic!("Non-UTF-8 filepath encountered: {}", entry.path().display())
-                        });
-                    let relative_path =
-                        RelativePath(path.strip_prefix(&workspace.source_dir).unwrap().to_owned());
-                    let mut file = File::open(&path).unwrap();
-                    let mut contents = Vec::new();
-                    file.read_to_end(&mut contents).unwrap();
-                    let hash = Hash(seahash::hash(&contents));
-
-                    let size = contents.len() as u64;
-                    let timestamp = file.metadata().unwrap().modified().unwrap();
-
-                    entries_clone.lock().unwrap().insert(
-                        relative_path.clone(),
-                        HashedFile {
-                            path: relative_path,
-                            hash,
-                            size,
-                            timestamp,
-                        },
-                    );
-                }
+                let record_error = |e: &dyn std::fmt::Display| {
+                    let mut errors = errors_clone.lock().unwrap();
+                    errors.0 += 1;
+                    errors.1.get_or_insert_with(|| e.to_string());
+                };
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    // e.g. a directory we can't list, which is recorded as
+                    // unreadable on its own
+                    Err(e) => {
+                        debug!("⚠️ Failed to walk: {}", e);
+                        return ignore::WalkState::Continue;
+                    }
+                };
+                let kind = match entry.file_type() {
+                    Some(ft) if ft.is_file() => EntryKind::File,
+                    // The source directory itself isn't tracked
+                    Some(ft) if ft.is_dir() && entry.depth() > 0 => EntryKind::Directory,
+                    Some(ft) if ft.is_symlink() => EntryKind::Symlink,
+                    _ => return ignore::WalkState::Continue,
+                };
+                let path = Utf8PathBuf::try_from(entry.path().to_owned()).unwrap_or_else(|_| {
+                    panic!("Non-UTF-8 filepath encountered: {}", entry.path().display())
+                });
+                let relative_path =
+                    RelativePath(path.strip_prefix(&workspace.source_dir).unwrap().to_owned());
+
+                // Before reading anything, which may bump the atime
+                let metadata = match fs::symlink_metadata(&path) {
+                    Ok(metadata) => metadata,
+                    // Removed since it was listed
+                    Err(e) => {
+                        record_error(&format_args!("{}: {}", relative_path, e));
+                        return ignore::WalkState::Continue;
+                    }
+                };
+                let timestamp = metadata.modified().unwrap();
+                let accessed = metadata.accessed().ok();
+                let mode = file_mode(&metadata);
+
+                let hashed = match kind {
+                    EntryKind::File => hash_file(&path),
+                    EntryKind::Directory => hash_directory(&path),
+                    EntryKind::Symlink => fs::read_link(&path).map(|target| {
+                        let target = target.as_os_str().as_encoded_bytes();
+                        (Hash(seahash::hash(target)), target.len() as u64)
+                    }),
+                };
+                let (hash, size) = match hashed {
+                    Ok(hashed) => hashed,
+                    Err(e) => {
+                        record_error(&format_args!("{}: {}", relative_path, e));
+                        unreadable_clone
+                            .lock()
+                            .unwrap()
+                            .insert(relative_path.clone());
+                        (Hash(0), metadata.len())
+                    }
+                };
+
+                entries_clone.lock().unwrap().insert(
+                    relative_path.clone(),
+                    HashedFile {
+                        path: relative_path,
+                        kind,
+                        hash,
+                        size,
+                        mode,
+                        timestamp,
+                        accessed,
+                        last_seen,
+                        last_seen_run: 0,
+                    },
+                );
                 ignore::WalkState::Continue
             })
         });
 
+    let (error_count, first_error) = Arc::try_unwrap(errors)
+        .unwrap_or_else(|_| unreachable!())
+        .into_inner()
+        .unwrap();
+    if let Some(first_error) = first_error {
+        warn!(
+            "⚠️ Failed to read {} entries, they're treated as dirty (e.g. {})",
+            error_count, first_error
+        );
+    }
+
     let entries = Arc::try_unwrap(entries)
         .unwrap_or_else(|_| unreachable!())
         .into_inner()
@@ -133,9 +302,49 @@ pub fn walk_source_dir(workspace: &Workspace) -> Cache {
 
     let mut source_dir = Cache::new(workspace.source_dir.clone());
     source_dir.entries = entries;
+    source_dir.unreadable = Arc::try_unwrap(unreadable)
+        .unwrap_or_else(|_| unreachable!())
+        .into_inner()
+        .unwrap();
     source_dir
 }
 
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0
+}
+
+/// Hashes a file's contents. Returns the hash and the file's size.
+fn hash_file(path: &Utf8Path) -> std::io::Result<(Hash, u64)> {
+    let mut file = File::open(path)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok((Hash(seahash::hash(&contents)), contents.len() as u64))
+}
+
+/// Hashes the sorted names of a directory's children, all of them, including
+/// ignored ones: creating any child bumps the directory's mtime. Returns the
+/// hash and the number of children.
+fn hash_directory(path: &Utf8Path) -> std::io::Result<(Hash, u64)> {
+    let mut names = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    names.sort();
+
+    let mut contents = Vec::new();
+    for name in &names {
+        contents.extend_from_slice(name.as_encoded_bytes());
+        contents.push(b'\0');
+    }
+    Ok((Hash(seahash::hash(&contents)), names.len() as u64))
+}
+
 use owo_colors::OwoColorize;
 use std::thread;
 
@@ -159,23 +368,57 @@ pub fn read_cache(cache_file: &Utf8PathBuf) -> Option<Cache> {
             return None;
         }
     };
+    decode_cache(&contents)
+}
+
+/// Reads the cache called `name` from `store`
+pub fn read_cache_from(store: &dyn CacheStore, name: &str) -> Option<Cache> {
+    read_sized_cache_from(store, name).map(|(cache, _)| cache)
+}
+
+/// Like [`read_cache_from`], also returning the cache's size in bytes
+fn read_sized_cache_from(store: &dyn CacheStore, name: &str) -> Option<(Cache, u64)> {
+    try_read_cache_from(store, name).unwrap_or_else(|problem| {
+        bad_cache_disclaimer(&problem);
+        None
+    })
+}
+
+/// Like [`read_sized_cache_from`], returning why the cache couldn't be used
+/// instead of logging it
+fn try_read_cache_from(store: &dyn CacheStore, name: &str) -> Result<Option<(Cache, u64)>, String> {
+    debug!("🔍 Reading cache {} in {}", name, store);
+    let contents = match store.get(name) {
+        Ok(Some(contents)) => contents,
+        Ok(None) => {
+            debug!("🆕 No cache {} in {}, starting fresh!", name, store);
+            return Ok(None);
+        }
+        Err(e) => return Err(format!("Failed to read cache {}: {}", name, e)),
+    };
+    let cache =
+        try_decode_cache(&contents).map_err(|problem| format!("Cache {}: {}", name, problem))?;
+    Ok(Some((cache, contents.len() as u64)))
+}
+
+/// Deserializes a cache, or returns `None` if it's corrupt or was made by
+/// another version of timelord
+pub fn decode_cache(contents: &[u8]) -> Option<Cache> {
+    try_decode_cache(contents)
+        .map_err(|problem| bad_cache_disclaimer(&problem))
+        .ok()
+}
 
+fn try_decode_cache(contents: &[u8]) -> Result<Cache, String> {
     let (source_dir, _) =
-        match bincode::serde::decode_from_slice::<Cache, _>(&contents, bincode::config::standard())
-        {
-            Ok(result) => result,
-            Err(e) => {
-                bad_cache_disclaimer(&format!("Failed to deserialize cache: {}", e));
-                return None;
-            }
-        };
+        bincode::serde::decode_from_slice::<Cache, _>(contents, bincode::config::standard())
+            .map_err(|e| format!("Failed to deserialize cache: {}", e))?;
 
     if source_dir.version != TIMELORD_CACHE_VERSION {
-        bad_cache_disclaimer("Cache file has wrong version, starting fresh!");
-        return None;
+        return Err("Cache file has wrong version, starting fresh!".to_owned());
     }
 
-    Some(source_dir)
+    Ok(source_dir)
 }
 
 pub fn read_or_create_cache(cache_file: &Utf8PathBuf) -> Cache {
@@ -192,6 +435,29 @@ pub fn read_or_create_cache(cache_file: &Utf8PathBuf) -> Cache {
     old_source_dir
 }
 
+/// Reads the first valid cache out of `names`, tried in order. Returns its
+/// name, the cache and its size in bytes, and why the caches tried before it
+/// couldn't be used.
+fn read_first_cache(
+    store: &dyn CacheStore,
+    names: &[String],
+) -> (Option<(String, Cache, u64)>, Vec<String>) {
+    let start = Instant::now();
+    let mut problems = Vec::new();
+    let found = names
+        .iter()
+        .find_map(|name| match try_read_cache_from(store, name) {
+            Ok(found) => found.map(|(cache, size)| (name.clone(), cache, size)),
+            Err(problem) => {
+                bad_cache_disclaimer(&problem);
+                problems.push(problem);
+                None
+            }
+        });
+    debug!("⏰ Deserialization took: {:?}", start.elapsed());
+    (found, problems)
+}
+
 fn scan_source_directory(workspace: &Workspace) -> Cache {
     debug!("🔍 Scanning source directory: {}", workspace.source_dir);
     let scan_start = Instant::now();
@@ -201,142 +467,1003 @@ fn scan_source_directory(workspace: &Workspace) -> Cache {
     new_source_dir
 }
 
-fn update_timestamps(old_source_dir: &Cache, new_source_dir: &mut Cache, workspace: &Workspace) {
+/// Options for [`sync_with_options`]
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// What timestamp new and dirty files should end up with
+    pub fallback: FallbackPolicy,
+    /// When set, no file is left with an mtime later than this. Files that
+    /// can't be restored from the cache are clamped down to it, and so are
+    /// restored timestamps. See [`source_date_epoch`].
+    pub source_date_epoch: Option<std::time::SystemTime>,
+    /// Touch every new and dirty file to a single "sync time", strictly greater
+    /// than every timestamp restored from the cache. This keeps dirty files newer
+    /// than fresh ones even when clocks disagree between runners. Directories
+    /// aren't touched.
+    pub touch_dirty: bool,
+    /// What to do with cached timestamps that are ahead of the local clock
+    pub clock_skew: ClockSkewPolicy,
+    /// Restore atimes along with mtimes for fresh files, for tools that rely
+    /// on them (like LRU pruners). Hashing files reads them, which may bump
+    /// their atime, so without this atimes aren't preserved.
+    pub preserve_atime: bool,
+    /// Carry over entries for paths missing from the source directory into
+    /// the new cache, as long as they were last seen within this long. Meant
+    /// for sparse checkouts, which would otherwise wipe entries that full
+    /// checkouts rely on.
+    pub retain_missing: Option<std::time::Duration>,
+    /// Restore the timestamp of new files whose contents exactly match a
+    /// single file from the old cache that isn't there anymore
+    pub detect_renames: bool,
+    /// Which database in the cache directory to use, see [`cache_file`].
+    /// Defaults to [`DEFAULT_CACHE_NAME`].
+    pub cache_name: Option<String>,
+    /// Names of caches to restore timestamps from, in order: the first valid
+    /// one wins. The new cache is still only written to
+    /// [`SyncOptions::cache_name`]. When empty, the cache is read from where
+    /// it'll be written. For PR builds, list the branch's own cache first,
+    /// then the main branch's.
+    pub read_from: Vec<String>,
+    /// Restore timestamps but never write the cache, for jobs that must not
+    /// modify a shared cache (fork PRs, release builds)
+    pub read_only: bool,
+}
+
+/// Reads the `SOURCE_DATE_EPOCH` environment variable, as specified by
+/// <https://reproducible-builds.org/specs/source-date-epoch/>
+pub fn source_date_epoch() -> Option<std::time::SystemTime> {
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+/// Decides the mtime of files that can't be restored from the cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FallbackPolicy {
+    /// Leave the mtime the checkout gave them
+    #[default]
+    Checkout,
+    /// Use the time of the last git commit that touched the file, like
+    /// `git-restore-mtime` does. Only new files get it: changed files,
+    /// untracked files and files with uncommitted changes keep their checkout
+    /// mtime.
+    GitCommitTime,
+}
+
+/// Decides what happens to cached timestamps that are in the future, which
+/// happens when the cache was produced on a runner with a fast clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockSkewPolicy {
+    /// Restore them anyway, but warn about it
+    #[default]
+    Warn,
+    /// Restore them as the current time instead
+    Clamp,
+    /// Don't restore them, leave the checkout mtime
+    Skip,
+}
+
+/// Why a file's timestamp couldn't be restored from the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirtyReason {
+    New,
+    HashChanged,
+    SizeChanged,
+    /// A directory's children were added, removed or renamed
+    ChildrenChanged,
+    /// The path used to be a different kind of entry, e.g. a file that became
+    /// a symlink
+    KindChanged,
+    /// The contents are the same but the executable bits changed, e.g.
+    /// `chmod +x`
+    ModeChanged,
+    /// The scan couldn't read the entry, so it can't tell whether it changed
+    Unreadable,
+}
+
+/// Where a file's mtime came from after a sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampSource {
+    /// Restored from the cache
+    Cache,
+    /// Left as-is from the checkout
+    Checkout,
+    /// Set to the time of the last git commit touching the file
+    GitCommit,
+    /// Clamped down to `SOURCE_DATE_EPOCH`
+    SourceDateEpoch,
+    /// Touched to the sync time, see [`SyncOptions::touch_dirty`]
+    SyncTime,
+    /// The cached timestamp was in the future and got clamped to the current
+    /// time, see [`ClockSkewPolicy::Clamp`]
+    Now,
+    /// Restored from the cache entry of a file with identical contents whose
+    /// path disappeared, see [`SyncOptions::detect_renames`]
+    Renamed,
+}
+
+impl TimestampSource {
+    /// Whether the timestamp came from the cache, under this path or another
+    pub fn is_restored(self) -> bool {
+        matches!(self, TimestampSource::Cache | TimestampSource::Renamed)
+    }
+}
+
+/// The outcome of a sync for a single file
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub path: RelativePath,
+    pub kind: EntryKind,
+    /// Why the file was dirty, or `None` if it was fresh
+    pub dirty_reason: Option<DirtyReason>,
+    /// Where the file's mtime came from
+    pub source: TimestampSource,
+    pub hash: Hash,
+    pub size: u64,
+    /// The file's mtime before the sync, as checked out
+    pub checkout_timestamp: std::time::SystemTime,
+    /// The mtime the old cache had for this path, if it had one
+    pub cached_timestamp: Option<std::time::SystemTime>,
+    /// The file's mtime after the sync
+    pub timestamp: std::time::SystemTime,
+    /// The path this file was at in the old cache, if it was restored as a
+    /// rename, see [`SyncOptions::detect_renames`]
+    pub renamed_from: Option<RelativePath>,
+    /// Set if we tried to change the file's mtime and failed
+    pub error: Option<RestoreError>,
+}
+
+/// Why setting a file's mtime, or saving the cache, failed
+#[derive(Debug, Clone)]
+pub struct RestoreError {
+    /// The OS error code, if any
+    pub errno: Option<i32>,
+    pub message: String,
+}
+
+impl From<std::io::Error> for RestoreError {
+    fn from(e: std::io::Error) -> Self {
+        RestoreError {
+            errno: e.raw_os_error(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+/// Summary of what a sync did
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub fresh: usize,
+    pub dirty: usize,
+    /// The time dirty files were touched to, if [`SyncOptions::touch_dirty`] was set
+    pub sync_time: Option<std::time::SystemTime>,
+    /// Timestamps from the cache that were ahead of the local clock
+    pub clock_skew: ClockSkewReport,
+    /// How precisely the filesystem stored the timestamps we set
+    pub precision: PrecisionReport,
+    /// How many atimes were restored, if [`SyncOptions::preserve_atime`] was set
+    pub atimes_restored: Option<usize>,
+    /// One entry per file in the source directory, sorted by path
+    pub files: Vec<FileReport>,
+    /// Entries that were in the old cache but aren't in the source directory
+    /// anymore, sorted by path. Only entries the previous scan saw count.
+    pub deleted: Vec<MissingEntry>,
+    /// Entries that were in the old cache but missing from its scan too,
+    /// carried over by [`SyncOptions::retain_missing`], sorted by path
+    pub retained: Vec<MissingEntry>,
+    /// The name of the cache timestamps were restored from, `None` if no
+    /// valid cache was found
+    pub cache_source: Option<String>,
+    /// When that cache was crawled
+    pub cache_crawl_time: Option<std::time::SystemTime>,
+    /// The host that cache was made on
+    pub cache_hostname: Option<String>,
+    /// Why caches couldn't be restored from, like corrupt or outdated ones,
+    /// in the order they were tried
+    pub cache_problems: Vec<String>,
+    /// Why the new cache couldn't be saved, if it couldn't
+    pub save_error: Option<RestoreError>,
+}
+
+/// An entry of the old cache that a sync didn't find in the source directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingEntry {
+    pub path: RelativePath,
+    pub kind: EntryKind,
+}
+
+/// Summary of cached timestamps found to be ahead of the local clock
+#[derive(Debug, Default, Clone)]
+pub struct ClockSkewReport {
+    /// How far the cache's crawl time was ahead of the local clock, if it was
+    pub crawl_time_ahead: Option<std::time::Duration>,
+    /// How many restorable timestamps were in the future
+    pub future_timestamps: usize,
+    /// How far the furthest of those was ahead of the local clock
+    pub max_ahead: std::time::Duration,
+}
+
+/// How precisely the filesystem stored the timestamps we set
+#[derive(Debug, Default, Clone)]
+pub struct PrecisionReport {
+    /// The mtime granularity probed on the source directory's filesystem.
+    /// If probing failed, the coarsest one read-back mtimes were normalized
+    /// to, if any.
+    pub granularity: Option<std::time::Duration>,
+    /// Timestamps that came back truncated or rounded to that granularity
+    pub normalized: usize,
+    /// Timestamps that came back further off than that
+    pub mismatched: usize,
+}
+
+impl SyncReport {
+    /// Counts the files whose mtime came from `source`
+    pub fn count_from(&self, source: TimestampSource) -> usize {
+        self.files.iter().filter(|f| f.source == source).count()
+    }
+
+    /// Files whose mtime we failed to set
+    pub fn failures(&self) -> impl Iterator<Item = &FileReport> {
+        self.files.iter().filter(|f| f.error.is_some())
+    }
+}
+
+fn dirty_reason(old_entry: Option<&HashedFile>, new_entry: &HashedFile) -> Option<DirtyReason> {
+    if let Some(old_entry) = old_entry {
+        if new_entry.kind != old_entry.kind {
+            Some(DirtyReason::KindChanged)
+        } else if new_entry.kind == EntryKind::Directory
+            && (new_entry.hash != old_entry.hash || new_entry.size != old_entry.size)
+        {
+            Some(DirtyReason::ChildrenChanged)
+        } else if new_entry.hash != old_entry.hash {
+            Some(DirtyReason::HashChanged)
+        } else if new_entry.size != old_entry.size {
+            Some(DirtyReason::SizeChanged)
+        } else if new_entry.tracked_mode() != old_entry.tracked_mode() {
+            Some(DirtyReason::ModeChanged)
+        } else {
+            None
+        }
+    } else {
+        Some(DirtyReason::New)
+    }
+}
+
+fn git_fallback_times(
+    old_source_dir: &Cache,
+    new_source_dir: &Cache,
+    workspace: &Workspace,
+    options: &SyncOptions,
+) -> HashMap<RelativePath, std::time::SystemTime> {
+    if options.fallback != FallbackPolicy::GitCommitTime {
+        return HashMap::new();
+    }
+
+    let wanted: HashSet<RelativePath> = new_source_dir
+        .entries
+        .iter()
+        .filter(|(_, entry)| entry.kind != EntryKind::Directory)
+        // A changed file's last commit may well be older than the build
+        // outputs made from its previous contents, which would then look fresh
+        .filter(|(path, entry)| {
+            dirty_reason(old_source_dir.entries.get(*path), entry) == Some(DirtyReason::New)
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    if wanted.is_empty() {
+        return HashMap::new();
+    }
+
+    if git::is_shallow(&workspace.source_dir) {
+        warn!(
+            "⚠️ {} is a shallow clone, files not touched since its oldest commit get that commit's time",
+            workspace.source_dir
+        );
+    }
+
+    let git_start = Instant::now();
+    match git::last_commit_times(&workspace.source_dir, &wanted) {
+        Ok(times) => {
+            debug!(
+                "⏰ Found commit times for {}/{} new files in {:?}",
+                times.len(),
+                wanted.len(),
+                git_start.elapsed()
+            );
+            times
+        }
+        Err(e) => {
+            warn!("❌ Failed to get commit times from git: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Looks up old cache entries by contents, for rename detection
+struct RenameIndex<'a> {
+    by_contents: HashMap<(EntryKind, Hash, u64, u32), Vec<&'a HashedFile>>,
+}
+
+impl<'a> RenameIndex<'a> {
+    /// Indexes the old entries whose paths aren't in the new scan
+    fn new(old_source_dir: &'a Cache, new_source_dir: &Cache) -> Self {
+        let mut by_contents: HashMap<_, Vec<_>> = HashMap::new();
+        for (path, entry) in &old_source_dir.entries {
+            if entry.kind == EntryKind::Directory || new_source_dir.entries.contains_key(path) {
+                continue;
+            }
+            by_contents
+                .entry((entry.kind, entry.hash, entry.size, entry.tracked_mode()))
+                .or_default()
+                .push(entry);
+        }
+        RenameIndex { by_contents }
+    }
+
+    /// Finds the old entry `new_entry` was moved from, if exactly one matches
+    fn original_of(&self, new_entry: &HashedFile) -> Option<&'a HashedFile> {
+        let key = (
+            new_entry.kind,
+            new_entry.hash,
+            new_entry.size,
+            new_entry.tracked_mode(),
+        );
+        match self.by_contents.get(&key)?.as_slice() {
+            [original] => Some(original),
+            _ => None,
+        }
+    }
+}
+
+/// Reads back mtimes right after setting them, to catch filesystems that
+/// store them less precisely than we'd like
+struct ReadBack {
+    granularity: Option<std::time::Duration>,
+    /// The coarsest granularity read-back mtimes were normalized to, in
+    /// nanoseconds, when probing failed
+    guessed_nanos: AtomicU64,
+    normalized: AtomicUsize,
+    mismatched: AtomicUsize,
+}
+
+impl ReadBack {
+    fn new(granularity: Option<std::time::Duration>) -> Self {
+        ReadBack {
+            granularity,
+            guessed_nanos: AtomicU64::new(0),
+            normalized: AtomicUsize::new(0),
+            mismatched: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the mtime the filesystem actually stored after we set `target`.
+    /// Anything within the probed granularity counts as normalized, anything
+    /// further off as a mismatch. If probing failed, anything truncated or
+    /// rounded to a granularity we know of counts as normalized.
+    fn mtime_after_set(
+        &self,
+        path: &Utf8Path,
+        target: std::time::SystemTime,
+    ) -> std::time::SystemTime {
+        let actual = match fs::symlink_metadata(path).and_then(|m| m.modified()) {
+            Ok(actual) => actual,
+            Err(e) => {
+                warn!("⚠️ Failed to read back mtime of {}: {}", path, e);
+                self.mismatched.fetch_add(1, Ordering::Relaxed);
+                return target;
+            }
+        };
+        if actual == target {
+            return actual;
+        }
+
+        let off_by = match actual.duration_since(target) {
+            Ok(d) => d,
+            Err(e) => e.duration(),
+        };
+        let normalized = match self.granularity {
+            Some(granularity) => off_by <= granularity,
+            None => {
+                let guessed = times::granularity_of(target, actual);
+                let known = times::GRANULARITIES.contains(&guessed);
+                if known {
+                    self.guessed_nanos
+                        .fetch_max(guessed.as_nanos() as u64, Ordering::Relaxed);
+                }
+                known
+            }
+        };
+        if normalized {
+            self.normalized.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.mismatched.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "⚠️ mtime of {} read back as {} instead of {} ({:?} off)",
+                path,
+                format_timestamp(actual),
+                format_timestamp(target),
+                off_by
+            );
+        }
+        actual
+    }
+
+    fn report(&self) -> PrecisionReport {
+        let guessed = match self.guessed_nanos.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(std::time::Duration::from_nanos(nanos)),
+        };
+        PrecisionReport {
+            granularity: self.granularity.or(guessed),
+            normalized: self.normalized.load(Ordering::Relaxed),
+            mismatched: self.mismatched.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn probe_granularity(workspace: &Workspace) -> Option<std::time::Duration> {
+    match times::probe_granularity(&workspace.source_dir) {
+        Ok(granularity) => {
+            debug!(
+                "⏰ Filesystem stores mtimes with {:?} granularity",
+                granularity
+            );
+            Some(granularity)
+        }
+        Err(e) => {
+            warn!(
+                "⚠️ Failed to probe mtime granularity in {}: {}",
+                workspace.source_dir, e
+            );
+            None
+        }
+    }
+}
+
+fn update_timestamps(
+    old_source_dir: &Cache,
+    new_source_dir: &mut Cache,
+    workspace: &Workspace,
+    options: &SyncOptions,
+    granularity: Option<std::time::Duration>,
+) -> SyncReport {
     debug!("⏰ Updating file timestamps...");
     let update_start = Instant::now();
 
+    let git_times = git_fallback_times(old_source_dir, new_source_dir, workspace, options);
+
+    let now = std::time::SystemTime::now();
+    let crawl_time_ahead = old_source_dir.crawl_time.duration_since(now).ok();
+    if let Some(ahead) = crawl_time_ahead {
+        warn!(
+            "⚠️ Cache was crawled {} in the future, the runner that made it probably had a fast clock",
+            humantime::format_duration(ahead)
+        );
+    }
+
     let fresh_count = AtomicUsize::new(0);
     let dirty_count = AtomicUsize::new(0);
+    let future_count = AtomicUsize::new(0);
+    let max_ahead_nanos = AtomicU64::new(0);
+    let read_back = ReadBack::new(granularity);
+    let atime_count = AtomicUsize::new(0);
     let entries_mutex = Arc::new(Mutex::new(()));
 
-    new_source_dir
-        .entries
-        .par_iter_mut()
-        .for_each(|(path, new_entry)| {
-            #[derive(Debug)]
-            enum DirtyReason {
-                New,
-                HashChanged,
-                SizeChanged,
-            }
+    let renames = options
+        .detect_renames
+        .then(|| RenameIndex::new(old_source_dir, new_source_dir));
+    let unreadable = std::mem::take(&mut new_source_dir.unreadable);
 
-            let old_entry = old_source_dir.entries.get(path);
-            let cause = if let Some(old_entry) = old_entry {
-                if new_entry.hash != old_entry.hash {
-                    Some(DirtyReason::HashChanged)
-                } else if new_entry.size != old_entry.size {
-                    Some(DirtyReason::SizeChanged)
-                } else {
-                    None
-                }
-            } else {
-                Some(DirtyReason::New)
-            };
+    let process = |path: &RelativePath, new_entry: &mut HashedFile| {
+        let old_entry = old_source_dir.entries.get(path);
+        let checkout_timestamp = new_entry.timestamp;
+        let mut cause = if unreadable.contains(path) {
+            Some(DirtyReason::Unreadable)
+        } else {
+            dirty_reason(old_entry, new_entry)
+        };
 
-            if let Some(cause) = cause {
-                dirty_count.fetch_add(1, Ordering::Relaxed);
-                let dirty_count_so_far = dirty_count.load(Ordering::Relaxed);
-                if dirty_count_so_far <= 5 {
-                    let _lock = entries_mutex.lock().unwrap();
-                    debug!(
-                        "  {} {} ({}, {}) - {:?}",
-                        "[dirty]".red(),
-                        path.0,
-                        new_entry.hash,
-                        human_bytes::human_bytes(new_entry.size as f64),
-                        cause
-                    );
-                } else if dirty_count_so_far == 5 {
-                    let _lock = entries_mutex.lock().unwrap();
-                    debug!("  {}", "(other dirty files ignored)");
+        // A new path may just be an old file that moved
+        let mut renamed_from = None;
+        if cause == Some(DirtyReason::New)
+            && let Some(original) = renames.as_ref().and_then(|r| r.original_of(new_entry))
+        {
+            renamed_from = Some(original);
+            cause = None;
+        }
+        let restore_from = match cause {
+            None => renamed_from.or(old_entry),
+            Some(_) => None,
+        };
+
+        // Decide which timestamp the file should end up with...
+        let (mut target, mut source) = match restore_from {
+            Some(restore_from) if renamed_from.is_some() => {
+                (restore_from.timestamp, TimestampSource::Renamed)
+            }
+            Some(restore_from) => (restore_from.timestamp, TimestampSource::Cache),
+            None => match git_times.get(path) {
+                Some(&commit_time) => (commit_time, TimestampSource::GitCommit),
+                None => (new_entry.timestamp, TimestampSource::Checkout),
+            },
+        };
+        if source.is_restored()
+            && let Ok(ahead) = target.duration_since(now)
+            && !ahead.is_zero()
+        {
+            future_count.fetch_add(1, Ordering::Relaxed);
+            max_ahead_nanos.fetch_max(ahead.as_nanos() as u64, Ordering::Relaxed);
+            match options.clock_skew {
+                ClockSkewPolicy::Warn => {}
+                ClockSkewPolicy::Clamp => {
+                    target = now;
+                    source = TimestampSource::Now;
                 }
-            } else {
-                let old_entry = old_entry.unwrap();
-                if new_entry.timestamp != old_entry.timestamp {
-                    let absolute_path = path.to_absolute_path(workspace);
-                    if let Ok(f) = std::fs::File::open(&absolute_path) {
-                        if f.set_modified(old_entry.timestamp).is_ok() {
-                            // Update the cache entry with the restored timestamp
-                            new_entry.timestamp = old_entry.timestamp;
-                        } else {
-                            let _lock = entries_mutex.lock().unwrap();
-                            warn!("❌ Failed to set mtime for {}", absolute_path);
-                        }
+                ClockSkewPolicy::Skip => {
+                    target = new_entry.timestamp;
+                    source = TimestampSource::Checkout;
+                }
+            }
+        }
+        if let Some(epoch) = options.source_date_epoch
+            && target > epoch
+        {
+            target = epoch;
+            source = TimestampSource::SourceDateEpoch;
+        }
+
+        // Restored files get their atime back too, if asked
+        let target_atime = match restore_from {
+            Some(restore_from) if options.preserve_atime && source.is_restored() => {
+                restore_from.accessed
+            }
+            _ => None,
+        };
+
+        // ...then apply it
+        let mut error = None;
+        if new_entry.timestamp != target
+            || (target_atime.is_some() && new_entry.accessed != target_atime)
+        {
+            let absolute_path = path.to_absolute_path(workspace);
+            match times::set_times(&absolute_path, target, target_atime) {
+                Ok(()) => {
+                    // Update the cache entry with the restored timestamp, as stored
+                    new_entry.timestamp = read_back.mtime_after_set(&absolute_path, target);
+                    if target_atime.is_some() {
+                        new_entry.accessed = target_atime;
+                        atime_count.fetch_add(1, Ordering::Relaxed);
                     }
                 }
-                let fresh_count_so_far = fresh_count.fetch_add(1, Ordering::Relaxed);
-                #[allow(clippy::comparison_chain)]
-                if fresh_count_so_far < 5 {
-                    let _lock = entries_mutex.lock().unwrap();
-                    debug!(
-                        "  {} {} ({}, {}, {} => {})",
-                        "[fresh]".green(),
-                        path.0,
-                        new_entry.hash,
-                        human_bytes::human_bytes(new_entry.size as f64),
-                        format_timestamp(old_entry.timestamp),
-                        format_timestamp_diff(old_entry.timestamp, new_entry.timestamp)
-                    );
-                } else if fresh_count_so_far == 5 {
+                Err(e) => {
+                    source = TimestampSource::Checkout;
                     let _lock = entries_mutex.lock().unwrap();
-                    debug!("  {}", "(other fresh files ignored)");
+                    warn!("❌ Failed to set mtime for {}: {}", absolute_path, e);
+                    error = Some(RestoreError::from(e));
                 }
             }
-        });
+        }
+
+        if let Some(cause) = cause {
+            dirty_count.fetch_add(1, Ordering::Relaxed);
+            let dirty_count_so_far = dirty_count.load(Ordering::Relaxed);
+            if dirty_count_so_far <= 5 {
+                let _lock = entries_mutex.lock().unwrap();
+                debug!(
+                    "  {} {} ({}, {}) - {:?} ({:?})",
+                    "[dirty]".red(),
+                    path.0,
+                    new_entry.hash,
+                    human_bytes::human_bytes(new_entry.size as f64),
+                    cause,
+                    source
+                );
+            } else if dirty_count_so_far == 5 {
+                let _lock = entries_mutex.lock().unwrap();
+                debug!("  {}", "(other dirty files ignored)");
+            }
+        } else {
+            let old_entry = restore_from.unwrap();
+            let fresh_count_so_far = fresh_count.fetch_add(1, Ordering::Relaxed);
+            #[allow(clippy::comparison_chain)]
+            if fresh_count_so_far < 5 {
+                let _lock = entries_mutex.lock().unwrap();
+                debug!(
+                    "  {} {} ({}, {}, {} => {})",
+                    "[fresh]".green(),
+                    path.0,
+                    new_entry.hash,
+                    human_bytes::human_bytes(new_entry.size as f64),
+                    format_timestamp(old_entry.timestamp),
+                    format_timestamp_diff(old_entry.timestamp, new_entry.timestamp)
+                );
+            } else if fresh_count_so_far == 5 {
+                let _lock = entries_mutex.lock().unwrap();
+                debug!("  {}", "(other fresh files ignored)");
+            }
+        }
+
+        FileReport {
+            path: path.clone(),
+            kind: new_entry.kind,
+            dirty_reason: cause,
+            source,
+            hash: new_entry.hash,
+            size: new_entry.size,
+            checkout_timestamp,
+            cached_timestamp: old_entry.map(|old_entry| old_entry.timestamp),
+            timestamp: new_entry.timestamp,
+            renamed_from: renamed_from.map(|original| original.path.clone()),
+            error,
+        }
+    };
+
+    let mut files: Vec<FileReport> = new_source_dir
+        .entries
+        .par_iter_mut()
+        .filter(|(_, entry)| entry.kind != EntryKind::Directory)
+        .map(|(path, new_entry)| process(path, new_entry))
+        .collect();
+
+    let sync_time = if options.touch_dirty {
+        Some(touch_dirty_files(
+            &mut files,
+            new_source_dir,
+            workspace,
+            options,
+            &read_back,
+        ))
+    } else {
+        None
+    };
+
+    // Directories go last, deepest first, so nothing bumps them after they're restored
+    let mut directories: Vec<_> = new_source_dir
+        .entries
+        .iter_mut()
+        .filter(|(_, entry)| entry.kind == EntryKind::Directory)
+        .collect();
+    directories.sort_by_key(|(path, _)| std::cmp::Reverse(path.0.components().count()));
+    files.extend(
+        directories
+            .into_iter()
+            .map(|(path, new_entry)| process(path, new_entry)),
+    );
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let clock_skew = ClockSkewReport {
+        crawl_time_ahead,
+        future_timestamps: future_count.load(Ordering::Relaxed),
+        max_ahead: std::time::Duration::from_nanos(max_ahead_nanos.load(Ordering::Relaxed)),
+    };
+    if clock_skew.future_timestamps > 0 {
+        warn!(
+            "⚠️ {} cached timestamps were in the future (up to {} ahead), policy: {:?}",
+            clock_skew.future_timestamps,
+            humantime::format_duration(clock_skew.max_ahead),
+            options.clock_skew
+        );
+    }
+
+    // Only the new scan was walked so far, so anything left over is gone,
+    // unless it's in a directory that couldn't be listed. Entries the
+    // previous scan didn't see either were carried over by `retain_missing`,
+    // they were already reported as deleted back then.
+    let (mut deleted, mut retained) = (Vec::new(), Vec::new());
+    for (path, entry) in &old_source_dir.entries {
+        if new_source_dir.entries.contains_key(path)
+            || unreadable.iter().any(|dir| path.0.starts_with(&dir.0))
+        {
+            continue;
+        }
+        let missing = MissingEntry {
+            path: path.clone(),
+            kind: entry.kind,
+        };
+        if entry.last_seen_run == old_source_dir.run {
+            deleted.push(missing);
+        } else {
+            retained.push(missing);
+        }
+    }
+    // Their hash is made up, next time they'll be new
+    for path in &unreadable {
+        new_source_dir.entries.remove(path);
+    }
+    for (i, entry) in deleted.iter().enumerate() {
+        if i < 5 {
+            debug!("  {} {}", "[deleted]".yellow(), entry.path);
+        } else {
+            debug!("  {}", "(other deleted files ignored)");
+            break;
+        }
+    }
+
+    let precision = read_back.report();
+    if precision.normalized > 0 || precision.mismatched > 0 {
+        debug!(
+            "⏰ Read back {} normalized and {} mismatched mtimes",
+            precision.normalized, precision.mismatched
+        );
+    }
 
     let fresh_count = fresh_count.load(Ordering::Relaxed);
     let dirty_count = dirty_count.load(Ordering::Relaxed);
     let update_time = update_start.elapsed();
     debug!(
-        "⏰ Spent {:?} syncing ({} fresh, {} dirty)",
-        update_time, fresh_count, dirty_count
+        "⏰ Spent {:?} syncing ({} fresh, {} dirty, {} deleted)",
+        update_time,
+        fresh_count,
+        dirty_count,
+        deleted.len()
     );
+
+    SyncReport {
+        fresh: fresh_count,
+        dirty: dirty_count,
+        sync_time,
+        clock_skew,
+        precision,
+        atimes_restored: options
+            .preserve_atime
+            .then(|| atime_count.load(Ordering::Relaxed)),
+        files,
+        deleted,
+        retained,
+        cache_source: None,
+        cache_crawl_time: None,
+        cache_hostname: None,
+        cache_problems: Vec::new(),
+        save_error: None,
+    }
 }
 
-fn save_new_cache(new_source_dir: &Cache, cache_file: &Utf8PathBuf) {
-    debug!("💾 Saving new cache to {}", cache_file);
-    let serialize_start = Instant::now();
-    let serialized = bincode::serde::encode_to_vec(new_source_dir, bincode::config::standard())
-        .expect("Failed to serialize new source dir");
+/// Touches every new and dirty file to a single "sync time", strictly greater
+/// than every timestamp restored from the cache, and returns that time.
+fn touch_dirty_files(
+    files: &mut [FileReport],
+    new_source_dir: &mut Cache,
+    workspace: &Workspace,
+    options: &SyncOptions,
+    read_back: &ReadBack,
+) -> std::time::SystemTime {
+    let max_restored = files
+        .iter()
+        .filter(|f| f.source.is_restored())
+        .map(|f| f.timestamp)
+        .max();
+    let now = std::time::SystemTime::now();
+    // At least a whole second, so filesystems with coarse mtimes still see a difference
+    let gap = std::time::Duration::from_secs(1).max(read_back.granularity.unwrap_or_default());
+    let mut sync_time = match max_restored {
+        Some(max_restored) => now.max(max_restored + gap),
+        None => now,
+    };
+    let mut source = TimestampSource::SyncTime;
+    if let Some(epoch) = options.source_date_epoch
+        && sync_time > epoch
+    {
+        warn!(
+            "⚠️ Sync time {} is past SOURCE_DATE_EPOCH, dirty files won't be newer than restored ones",
+            format_timestamp(sync_time)
+        );
+        sync_time = epoch;
+        source = TimestampSource::SourceDateEpoch;
+    }
+
+    files
+        .par_iter_mut()
+        .filter(|f| f.kind != EntryKind::Directory && f.dirty_reason.is_some())
+        .for_each(|file| {
+            if file.timestamp != sync_time {
+                let absolute_path = file.path.to_absolute_path(workspace);
+                if let Err(e) = times::set_mtime(&absolute_path, sync_time) {
+                    warn!("❌ Failed to set mtime for {}: {}", absolute_path, e);
+                    file.error = Some(RestoreError::from(e));
+                    return;
+                }
+                file.timestamp = read_back.mtime_after_set(&absolute_path, sync_time);
+            }
+            file.source = source;
+        });
 
-    // Create the directory if it doesn't exist
-    if let Some(parent) = cache_file.parent() {
-        fs::create_dir_all(parent).expect("Failed to create cache directory");
+    for file in files
+        .iter()
+        .filter(|f| f.kind != EntryKind::Directory && f.dirty_reason.is_some())
+    {
+        if let Some(entry) = new_source_dir.entries.get_mut(&file.path) {
+            entry.timestamp = file.timestamp;
+        }
+    }
+
+    debug!(
+        "⏰ Touched dirty files to sync time {}",
+        format_timestamp(sync_time)
+    );
+    sync_time
+}
+
+/// Moves entries for paths that aren't in the new scan from the old cache into
+/// the new one, unless they haven't been seen for longer than `max_age`.
+/// Returns how many were carried over.
+fn retain_missing_entries(
+    old_source_dir: Cache,
+    new_source_dir: &mut Cache,
+    max_age: std::time::Duration,
+) -> usize {
+    let now = std::time::SystemTime::now();
+    let mut retained = 0;
+    let mut expired = 0;
+    for (path, entry) in old_source_dir.entries {
+        if new_source_dir.entries.contains_key(&path) {
+            continue;
+        }
+        // Entries from the future (clock skew) count as just seen
+        let age = now.duration_since(entry.last_seen).unwrap_or_default();
+        if age > max_age {
+            expired += 1;
+            continue;
+        }
+        new_source_dir.entries.insert(path, entry);
+        retained += 1;
     }
+    debug!(
+        "🗃️ Carried over {} missing entries, {} expired",
+        retained, expired
+    );
+    retained
+}
 
-    let mut file = File::create(cache_file).expect("Failed to create cache file");
-    file.write_all(&serialized)
-        .expect("Failed to write cache file");
+fn encode_cache(cache: &Cache) -> Vec<u8> {
+    let serialize_start = Instant::now();
+    let serialized = bincode::serde::encode_to_vec(cache, bincode::config::standard())
+        .expect("Failed to serialize new source dir");
     let serialize_time = serialize_start.elapsed();
     debug!("⏰ Cache serialization took: {:?}", serialize_time);
+    serialized
+}
+
+/// Saves `cache` as the cache called `name` in `store`, returning its size in
+/// bytes
+fn save_cache_to(store: &dyn CacheStore, name: &str, cache: &Cache) -> std::io::Result<u64> {
+    debug!("💾 Saving new cache {} to {}", name, store);
+    let serialized = encode_cache(cache);
+    store.put(name, &serialized)?;
+    Ok(serialized.len() as u64)
+}
+
+/// Explains why the cache couldn't be saved, singling out read-only cache
+/// locations since those are usually a CI misconfiguration
+fn report_save_error(location: &dyn std::fmt::Display, e: &std::io::Error) {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem => {
+            bad_cache_disclaimer(&format!(
+                "Cache location {} is read-only, the new cache wasn't saved",
+                location
+            ));
+            warn!(
+                "   Use read-only mode to consume the cache without saving it: {}",
+                e
+            );
+        }
+        _ => bad_cache_disclaimer(&format!("Failed to save cache to {}: {}", location, e)),
+    }
+}
+
+pub fn sync(source_dir: Utf8PathBuf, cache_dir: Utf8PathBuf) -> SyncReport {
+    sync_with_options(source_dir, cache_dir, &SyncOptions::default())
 }
 
-pub fn sync(source_dir: Utf8PathBuf, cache_dir: Utf8PathBuf) {
-    let cache_file = cache_dir.join("timelord.db");
+pub fn sync_with_options(
+    source_dir: Utf8PathBuf,
+    cache_dir: Utf8PathBuf,
+    options: &SyncOptions,
+) -> SyncReport {
+    sync_with_store(source_dir, &FsStore::new(cache_dir), options)
+}
+
+/// Like [`sync_with_options`], reading and writing caches through `store`
+pub fn sync_with_store(
+    source_dir: Utf8PathBuf,
+    store: &dyn CacheStore,
+    options: &SyncOptions,
+) -> SyncReport {
+    let cache_name = options.cache_name.as_deref().unwrap_or(DEFAULT_CACHE_NAME);
     let start = Instant::now();
 
+    // Held until the new cache is saved, so concurrent jobs don't clobber it
+    let _lock = if options.read_only {
+        None
+    } else {
+        match store.lock(cache_name) {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("⚠️ Failed to lock cache {} in {}: {}", cache_name, store, e);
+                None
+            }
+        }
+    };
+
     let workspace = Workspace { source_dir };
 
-    let (old_source_dir, mut new_source_dir) = {
-        let cache_file = cache_file.clone();
-        let workspace = workspace.clone();
-        let cache_reader_handle = thread::spawn(move || {
-            let sd = read_or_create_cache(&cache_file);
-            print_cache_info(&sd, &cache_file);
-            sd
-        });
-        let source_scanner_handle = thread::spawn(move || scan_source_directory(&workspace));
-        (
-            cache_reader_handle.join().unwrap(),
-            source_scanner_handle.join().unwrap(),
-        )
+    // Before scanning, so the scan can't pick up the probe file
+    let granularity = probe_granularity(&workspace);
+
+    let read_from = if options.read_from.is_empty() {
+        vec![cache_name.to_owned()]
+    } else {
+        options.read_from.clone()
     };
 
+    let ((cache_source, old_source_dir, cache_problems), mut new_source_dir) =
+        thread::scope(|scope| {
+            let cache_reader_handle = scope.spawn(|| match read_first_cache(store, &read_from) {
+                (Some((cache_source, sd, size)), problems) => {
+                    print_cache_info(&sd, size);
+                    (Some(cache_source), sd, problems)
+                }
+                (None, problems) => {
+                    debug!("⚠️ Falling back to empty cache");
+                    (None, Cache::new(Utf8PathBuf::new()), problems)
+                }
+            });
+            let source_scanner_handle = scope.spawn(|| scan_source_directory(&workspace));
+            (
+                cache_reader_handle.join().unwrap(),
+                source_scanner_handle.join().unwrap(),
+            )
+        });
+    match &cache_source {
+        Some(cache_source) if cache_source != cache_name => {
+            info!("📥 Restoring timestamps from {} in {}", cache_source, store)
+        }
+        Some(_) => {}
+        None => info!("🆕 No valid cache to restore from, starting fresh"),
+    }
+    new_source_dir.run = old_source_dir.run + 1;
+    new_source_dir.read_from = cache_source.clone();
+    for entry in new_source_dir.entries.values_mut() {
+        entry.last_seen_run = new_source_dir.run;
+    }
+
     // First update timestamps
-    update_timestamps(&old_source_dir, &mut new_source_dir, &workspace);
+    let mut report = update_timestamps(
+        &old_source_dir,
+        &mut new_source_dir,
+        &workspace,
+        options,
+        granularity,
+    );
+    report.cache_source = cache_source;
+    report.cache_problems = cache_problems;
+    if report.cache_source.is_some() {
+        report.cache_crawl_time = Some(old_source_dir.crawl_time);
+        report.cache_hostname = Some(old_source_dir.hostname.clone());
+    }
 
     // Then save the new cache
-    save_new_cache(&new_source_dir, &cache_file);
+    let retained = match options.retain_missing {
+        Some(max_age) if !options.read_only => {
+            retain_missing_entries(old_source_dir, &mut new_source_dir, max_age)
+        }
+        _ => 0,
+    };
+    if options.read_only {
+        info!("🔒 Read-only, leaving the cache untouched");
+    } else if let Err(e) = save_cache_to(store, cache_name, &new_source_dir) {
+        report_save_error(store, &e);
+        report.save_error = Some(e.into());
+    }
 
     let total_time = start.elapsed();
     info!(
@@ -344,6 +1471,68 @@ pub fn sync(source_dir: Utf8PathBuf, cache_dir: Utf8PathBuf) {
         new_source_dir.entries.len(),
         total_time
     );
+    if options.detect_renames {
+        info!(
+            "   {} renamed files restored",
+            report.count_from(TimestampSource::Renamed)
+        );
+    }
+    if !report.deleted.is_empty() {
+        info!(
+            "   {} files deleted since the cache was made",
+            report.deleted.len()
+        );
+    }
+    if options.retain_missing.is_some() && !options.read_only {
+        info!(
+            "   {} missing entries carried over to the new cache",
+            retained
+        );
+    }
+    if options.fallback == FallbackPolicy::GitCommitTime {
+        info!(
+            "   {} new files got their git commit time",
+            report.count_from(TimestampSource::GitCommit)
+        );
+    }
+    if let Some(epoch) = options.source_date_epoch {
+        info!(
+            "   {} files clamped to SOURCE_DATE_EPOCH ({})",
+            report.count_from(TimestampSource::SourceDateEpoch),
+            format_timestamp(epoch)
+        );
+    }
+    if report.clock_skew.future_timestamps > 0 {
+        info!(
+            "   {} cached timestamps were ahead of the local clock (policy: {:?})",
+            report.clock_skew.future_timestamps, options.clock_skew
+        );
+    }
+    if report.precision.normalized > 0 || report.precision.mismatched > 0 {
+        info!(
+            "   {} mtimes normalized to the filesystem's {:?} granularity, {} mismatched",
+            report.precision.normalized,
+            report.precision.granularity.unwrap_or_default(),
+            report.precision.mismatched
+        );
+    }
+    match report.atimes_restored {
+        Some(count) => info!("   atime policy: preserve ({} atimes restored)", count),
+        None => debug!("   atime policy: ignore (atimes aren't restored)"),
+    }
+    let failures = report.failures().count();
+    if failures > 0 {
+        warn!("❌ Failed to set the mtime of {} files", failures);
+    }
+    if let Some(sync_time) = report.sync_time {
+        info!(
+            "   {} dirty files touched to {}",
+            report.count_from(TimestampSource::SyncTime),
+            format_timestamp(sync_time)
+        );
+    }
+
+    report
 }
 
 #[derive(Debug, Clone)]
@@ -396,44 +1585,230 @@ impl DirectoryInfo {
     }
 }
 
+/// Lists every cache database in `cache_dir`, with its source directory and
+/// age. More details are logged at debug level.
 pub fn cache_info(cache_dir: Utf8PathBuf) {
-    let cache_file = cache_dir.join("timelord.db");
-    if !cache_file.exists() {
-        warn!("❌ Cache file not found: {}", cache_file);
+    cache_info_in(&FsStore::new(cache_dir))
+}
+
+/// Like [`cache_info`], for every cache in `store`
+pub fn cache_info_in(store: &dyn CacheStore) {
+    let names = match store.list() {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("❌ Failed to list caches in {}: {}", store, e);
+            return;
+        }
+    };
+    if names.is_empty() {
+        warn!("❌ No caches found in {}", store);
         return;
     }
 
-    let source_dir = match read_cache(&cache_file) {
-        Some(source_dir) => source_dir,
-        None => {
-            warn!("❌ Failed to read cache file: {}", cache_file);
-            return;
+    for name in names {
+        let Some((cache, size)) = read_sized_cache_from(store, &name) else {
+            warn!("❌ {}: failed to read cache", name);
+            continue;
+        };
+        info!(
+            "📦 {}: {} entries from {}, crawled {}{}",
+            name,
+            cache.entries.len(),
+            cache.absolute_path,
+            format_age(cache.crawl_time),
+            match &cache.read_from {
+                Some(read_from) if *read_from != name => {
+                    format!(", restored from {}", read_from)
+                }
+                _ => String::new(),
+            }
+        );
+        print_cache_info(&cache, size);
+    }
+}
+
+/// What [`gc`] did to a cache
+#[derive(Debug)]
+pub struct GcReport {
+    pub pruned: usize,
+    pub kept: usize,
+    /// Size of the cache before and after pruning
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Prunes entries last seen longer than `max_age` ago or more than `max_runs`
+/// runs ago from the cache database `cache_name` in `cache_dir`. Returns `None`
+/// if there was no valid cache to prune, or if it couldn't be saved.
+pub fn gc(
+    cache_dir: Utf8PathBuf,
+    cache_name: &str,
+    max_age: Option<std::time::Duration>,
+    max_runs: Option<u64>,
+) -> Option<GcReport> {
+    gc_in(&FsStore::new(cache_dir), cache_name, max_age, max_runs)
+}
+
+/// Like [`gc`], for the cache called `cache_name` in `store`
+pub fn gc_in(
+    store: &dyn CacheStore,
+    cache_name: &str,
+    max_age: Option<std::time::Duration>,
+    max_runs: Option<u64>,
+) -> Option<GcReport> {
+    let _lock = store.lock(cache_name).ok().flatten();
+    let Some((mut cache, bytes_before)) = read_sized_cache_from(store, cache_name) else {
+        warn!("❌ No valid cache {} to prune in {}", cache_name, store);
+        return None;
+    };
+
+    let pruned = cache.prune(max_age, max_runs);
+    let bytes_after = if pruned == 0 {
+        bytes_before
+    } else {
+        match save_cache_to(store, cache_name, &cache) {
+            Ok(size) => size,
+            Err(e) => {
+                report_save_error(store, &e);
+                return None;
+            }
         }
     };
-    print_cache_info(&source_dir, &cache_file);
+
+    let report = GcReport {
+        pruned,
+        kept: cache.entries.len(),
+        bytes_before,
+        bytes_after,
+    };
+    info!(
+        "🧹 Pruned {} entries, kept {}, reclaimed {}",
+        report.pruned,
+        report.kept,
+        human_bytes::human_bytes(bytes_before.saturating_sub(bytes_after) as f64)
+    );
+    Some(report)
 }
 
-fn print_cache_info(cache: &Cache, cache_file: &Utf8PathBuf) {
-    let cache_size = match fs::metadata(cache_file) {
-        Ok(metadata) => metadata.len(),
-        Err(_) => {
-            debug!("Cache not created yet");
-            return;
+/// How [`merge_caches`] picks between caches that disagree about a path's
+/// contents
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keep the entry from the most recently crawled cache
+    #[default]
+    NewestCrawl,
+    /// Keep the entry with the oldest timestamp
+    OldestTimestamp,
+}
+
+/// Combines caches made by parallel CI shards into one. Entries with the same
+/// contents are deduplicated, keeping the oldest timestamp; entries whose
+/// contents differ are picked according to `policy`. Returns `None` if there
+/// was nothing to merge.
+pub fn merge_caches(mut caches: Vec<Cache>, policy: MergePolicy) -> Option<Cache> {
+    // Newest crawl first, so it's the one kept on conflicts by default
+    caches.sort_by_key(|cache| std::cmp::Reverse(cache.crawl_time));
+    // Each shard counts its own runs, so `last_seen_run` is moved onto the
+    // highest counter: seen N runs before its shard's last, N runs before
+    // the merged cache's last
+    let run = caches.iter().map(|cache| cache.run).max()?;
+    for cache in &mut caches {
+        let behind = run - cache.run;
+        for entry in cache.entries.values_mut() {
+            entry.last_seen_run += behind;
+        }
+        cache.run = run;
+    }
+    let mut caches = caches.into_iter();
+    let mut merged = caches.next()?;
+    merged.read_from = None;
+
+    for cache in caches {
+        for (path, entry) in cache.entries {
+            let existing = match merged.entries.entry(path) {
+                std::collections::btree_map::Entry::Vacant(vacant) => {
+                    vacant.insert(entry);
+                    continue;
+                }
+                std::collections::btree_map::Entry::Occupied(occupied) => occupied.into_mut(),
+            };
+            let same_contents = (
+                existing.kind,
+                existing.hash,
+                existing.size,
+                existing.tracked_mode(),
+            ) == (entry.kind, entry.hash, entry.size, entry.tracked_mode());
+            if same_contents {
+                if entry.timestamp < existing.timestamp {
+                    existing.timestamp = entry.timestamp;
+                    existing.accessed = entry.accessed;
+                }
+                existing.last_seen = existing.last_seen.max(entry.last_seen);
+                existing.last_seen_run = existing.last_seen_run.max(entry.last_seen_run);
+                continue;
+            }
+            debug!("🔀 {} differs between caches ({:?})", entry.path, policy);
+            if policy == MergePolicy::OldestTimestamp && entry.timestamp < existing.timestamp {
+                *existing = entry;
+            }
         }
+    }
+    Some(merged)
+}
+
+/// Merges the cache files in `inputs` into `output`, skipping the ones that
+/// aren't valid caches. Returns the number of entries in the merged cache, or
+/// `None` if no input was valid or the result couldn't be saved. `output`
+/// is saved like a sync saves its cache, so it must end in `.db`.
+pub fn merge(inputs: &[Utf8PathBuf], output: &Utf8PathBuf, policy: MergePolicy) -> Option<usize> {
+    let (Some(name), Some("db")) = (output.file_stem(), output.extension()) else {
+        warn!("❌ {} must end in .db, like the caches it merges", output);
+        return None;
     };
+    let dir = output.parent().filter(|dir| !dir.as_str().is_empty());
+    let store = FsStore::new(dir.map_or_else(|| Utf8PathBuf::from("."), |dir| dir.to_owned()));
+    // Inputs may include the output, so it's locked before they're read
+    let _lock = store.lock(name).ok().flatten();
+
+    let caches = inputs
+        .iter()
+        .filter_map(|input| {
+            let cache = read_cache(input);
+            if cache.is_none() {
+                warn!("❌ Skipping {}, it isn't a valid cache", input);
+            }
+            cache
+        })
+        .collect::<Vec<_>>();
+    let valid = caches.len();
+    let Some(merged) = merge_caches(caches, policy) else {
+        warn!("❌ No valid cache to merge");
+        return None;
+    };
+    if let Err(e) = save_cache_to(&store, name, &merged) {
+        report_save_error(output, &e);
+        return None;
+    }
+    info!(
+        "🔀 Merged {} caches into {}, tracking {} entries",
+        valid,
+        output,
+        merged.entries.len()
+    );
+    Some(merged.entries.len())
+}
+
+fn print_cache_info(cache: &Cache, cache_size: u64) {
     debug!(
-        "   Cache is {}, tracking {} entries (version {})",
+        "   Cache is {}, tracking {} entries (version {}, run {})",
         human_bytes::human_bytes(cache_size as f64),
         cache.entries.len(),
         cache.version,
+        cache.run,
     );
     debug!(
-        "   Crawled {} ago ({}) on {} from source dir {}",
-        humantime::format_duration(
-            std::time::SystemTime::now()
-                .duration_since(cache.crawl_time)
-                .unwrap()
-        ),
+        "   Crawled {} ({}) on {} from source dir {}",
+        format_age(cache.crawl_time),
         format_timestamp(cache.crawl_time),
         cache.hostname,
         cache.absolute_path
@@ -442,19 +1817,35 @@ fn print_cache_info(cache: &Cache, cache_file: &Utf8PathBuf) {
     let mut root = DirectoryInfo::new();
     for (path, file) in &cache.entries {
         let mut current = &mut root;
-        for name in path.0.components().take(path.0.components().count() - 1) {
+        let depth = match file.kind {
+            EntryKind::File | EntryKind::Symlink => path.0.components().count() - 1,
+            EntryKind::Directory => path.0.components().count(),
+        };
+        for name in path.0.components().take(depth) {
             current = current
                 .subdirectories
                 .entry(name.to_string())
                 .or_insert_with(DirectoryInfo::new);
         }
-        current.add_file(file.size);
+        if file.kind != EntryKind::Directory {
+            current.add_file(file.size);
+        }
     }
 
     debug!("📁 Directory Structure:");
     root.print("  ", ".");
 }
 
+fn format_age(time: std::time::SystemTime) -> String {
+    match std::time::SystemTime::now().duration_since(time) {
+        Ok(age) => format!("{} ago", humantime::format_duration(age)),
+        Err(e) => format!(
+            "{} in the future (clock skew?)",
+            humantime::format_duration(e.duration())
+        ),
+    }
+}
+
 fn format_timestamp(timestamp: std::time::SystemTime) -> String {
     jiff::Timestamp::try_from(timestamp)
         .unwrap()
@@ -0,0 +1,198 @@
+//! Machine-readable reports of what a sync did to every file, for tooling
+//! that needs the list of changed paths
+
+use crate::{DirtyReason, EntryKind, MissingEntry, RelativePath, SyncReport, TimestampSource};
+use camino::Utf8Path;
+use serde::Serialize;
+use std::io::Write;
+
+/// How [`write_report`] formats a report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// An array with one object per file
+    #[default]
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// A header, then one row per file
+    Csv,
+}
+
+/// One line of a report. Deleted and retained entries only have a path,
+/// kind and status, the rest is `null`.
+#[derive(Serialize)]
+struct Row<'a> {
+    path: &'a RelativePath,
+    kind: &'static str,
+    status: &'static str,
+    dirty_reason: Option<&'static str>,
+    source: Option<&'static str>,
+    hash: Option<String>,
+    size: Option<u64>,
+    cached_timestamp: Option<String>,
+    checkout_timestamp: Option<String>,
+    new_timestamp: Option<String>,
+    renamed_from: Option<&'a RelativePath>,
+    error: Option<String>,
+}
+
+impl<'a> Row<'a> {
+    fn missing(entry: &'a MissingEntry, status: &'static str) -> Self {
+        Row {
+            path: &entry.path,
+            kind: kind_name(entry.kind),
+            status,
+            dirty_reason: None,
+            source: None,
+            hash: None,
+            size: None,
+            cached_timestamp: None,
+            checkout_timestamp: None,
+            new_timestamp: None,
+            renamed_from: None,
+            error: None,
+        }
+    }
+
+    /// The fields in [`COLUMNS`] order, empty for `null`
+    fn csv_fields(&self) -> [String; COLUMNS.len()] {
+        let field = |value: Option<&str>| value.map(csv_field).unwrap_or_default();
+        [
+            csv_field(self.path.0.as_str()),
+            self.kind.to_owned(),
+            self.status.to_owned(),
+            field(self.dirty_reason),
+            field(self.source),
+            field(self.hash.as_deref()),
+            self.size.map(|size| size.to_string()).unwrap_or_default(),
+            field(self.cached_timestamp.as_deref()),
+            field(self.checkout_timestamp.as_deref()),
+            field(self.new_timestamp.as_deref()),
+            field(self.renamed_from.map(|p| p.0.as_str())),
+            field(self.error.as_deref()),
+        ]
+    }
+}
+
+const COLUMNS: [&str; 12] = [
+    "path",
+    "kind",
+    "status",
+    "dirty_reason",
+    "source",
+    "hash",
+    "size",
+    "cached_timestamp",
+    "checkout_timestamp",
+    "new_timestamp",
+    "renamed_from",
+    "error",
+];
+
+/// Writes every file of `report` to `path`: its status (`fresh`, `dirty`,
+/// `deleted` or `retained`), dirty reason, hash, size, and timestamps before
+/// and after the sync. Deleted and retained files only have a path and kind.
+pub fn write_report(
+    report: &SyncReport,
+    path: &Utf8Path,
+    format: ReportFormat,
+) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let rows = rows(report);
+    match format {
+        ReportFormat::Json => {
+            writeln!(out, "[")?;
+            for (i, row) in rows.iter().enumerate() {
+                write!(out, "  ")?;
+                serde_json::to_writer(&mut out, row)?;
+                writeln!(out, "{}", if i + 1 < rows.len() { "," } else { "" })?;
+            }
+            writeln!(out, "]")?;
+        }
+        ReportFormat::Ndjson => {
+            for row in &rows {
+                serde_json::to_writer(&mut out, row)?;
+                writeln!(out)?;
+            }
+        }
+        ReportFormat::Csv => {
+            writeln!(out, "{}", COLUMNS.join(","))?;
+            for row in &rows {
+                writeln!(out, "{}", row.csv_fields().join(","))?;
+            }
+        }
+    }
+    out.flush()
+}
+
+fn rows(report: &SyncReport) -> Vec<Row<'_>> {
+    let timestamp = |t: std::time::SystemTime| {
+        jiff::Timestamp::try_from(t)
+            .map(|t| t.to_string())
+            .unwrap_or_default()
+    };
+    let mut rows: Vec<_> = report
+        .files
+        .iter()
+        .map(|file| Row {
+            path: &file.path,
+            kind: kind_name(file.kind),
+            status: match file.dirty_reason {
+                Some(_) => "dirty",
+                None => "fresh",
+            },
+            dirty_reason: file.dirty_reason.map(reason_name),
+            source: Some(source_name(file.source)),
+            hash: Some(file.hash.to_string()),
+            size: Some(file.size),
+            cached_timestamp: file.cached_timestamp.map(timestamp),
+            checkout_timestamp: Some(timestamp(file.checkout_timestamp)),
+            new_timestamp: Some(timestamp(file.timestamp)),
+            renamed_from: file.renamed_from.as_ref(),
+            error: file.error.as_ref().map(|e| e.to_string()),
+        })
+        .collect();
+    rows.extend(report.deleted.iter().map(|e| Row::missing(e, "deleted")));
+    rows.extend(report.retained.iter().map(|e| Row::missing(e, "retained")));
+    rows
+}
+
+fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::File => "file",
+        EntryKind::Directory => "directory",
+        EntryKind::Symlink => "symlink",
+    }
+}
+
+fn reason_name(reason: DirtyReason) -> &'static str {
+    match reason {
+        DirtyReason::New => "new",
+        DirtyReason::HashChanged => "hash_changed",
+        DirtyReason::SizeChanged => "size_changed",
+        DirtyReason::ChildrenChanged => "children_changed",
+        DirtyReason::KindChanged => "kind_changed",
+        DirtyReason::ModeChanged => "mode_changed",
+        DirtyReason::Unreadable => "unreadable",
+    }
+}
+
+fn source_name(source: TimestampSource) -> &'static str {
+    match source {
+        TimestampSource::Cache => "cache",
+        TimestampSource::Checkout => "checkout",
+        TimestampSource::GitCommit => "git_commit",
+        TimestampSource::SourceDateEpoch => "source_date_epoch",
+        TimestampSource::SyncTime => "sync_time",
+        TimestampSource::Now => "now",
+        TimestampSource::Renamed => "renamed",
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
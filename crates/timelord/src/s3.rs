@@ -0,0 +1,259 @@
+//! A [`CacheStore`] for S3-compatible object storage, using curl's SigV4
+//! request signing
+
+use crate::CacheStore;
+use crate::http::{curl, http_error};
+use log::*;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Credentials to sign S3 requests with
+#[derive(Clone)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// For temporary credentials
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    /// Reads `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and, if set,
+    /// `AWS_SESSION_TOKEN`
+    pub fn from_env() -> Option<Self> {
+        Some(S3Credentials {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+impl std::fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keeps caches as `<prefix><name>.db` objects in an S3-compatible bucket.
+/// Requests are signed and made by the `curl` binary (7.75 or later), which
+/// must be on the `PATH`.
+///
+/// Writes are conditional: a cache is only overwritten if it's still the one
+/// that was read (or still missing), so a concurrent job's newer cache isn't
+/// clobbered.
+#[derive(Debug)]
+pub struct S3Store {
+    endpoint: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    credentials: S3Credentials,
+    /// The ETag of each cache when it was read, `None` if it was missing
+    read_etags: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl S3Store {
+    /// A store for `bucket` on AWS, in `region`
+    pub fn new(
+        bucket: impl Into<String>,
+        region: impl Into<String>,
+        credentials: S3Credentials,
+    ) -> Self {
+        let region = region.into();
+        S3Store {
+            endpoint: format!("https://s3.{region}.amazonaws.com"),
+            bucket: bucket.into(),
+            prefix: String::new(),
+            region,
+            credentials,
+            read_etags: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Talks to another S3-compatible service, like `http://localhost:9000`
+    /// for MinIO. Buckets are addressed path-style.
+    pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Prepends `prefix` to object keys, like `timelord/main/` to key caches by
+    /// repository and branch
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.endpoint,
+            self.bucket,
+            percent_encode(&format!("{}{}.db", self.prefix, name), true)
+        )
+    }
+
+    /// The ETag of the object at `url`, `None` if there's no such object
+    fn current_etag(&self, url: &str) -> std::io::Result<Option<String>> {
+        let response = self.request(&["--head".to_owned(), url.to_owned()])?;
+        match response.status {
+            200 => Ok(response.etag),
+            404 => Ok(None),
+            status => Err(http_error("HEAD", url, status)),
+        }
+    }
+
+    /// Runs a signed request. Credentials go through curl's stdin rather than
+    /// its arguments, so they don't show up in the process list.
+    fn request(&self, args: &[String]) -> std::io::Result<crate::http::Response> {
+        let mut config = format!(
+            "user = \"{}:{}\"\n",
+            escape_config(&self.credentials.access_key_id),
+            escape_config(&self.credentials.secret_access_key)
+        );
+        if let Some(token) = &self.credentials.session_token {
+            config.push_str(&format!(
+                "header = \"x-amz-security-token: {}\"\n",
+                escape_config(token)
+            ));
+        }
+        let mut all_args = vec![
+            "--config".to_owned(),
+            "-".to_owned(),
+            "--aws-sigv4".to_owned(),
+            format!("aws:amz:{}:s3", self.region),
+            // S3 wants the payload hash in a header, and curl signs it as is
+            "--header".to_owned(),
+            "x-amz-content-sha256: UNSIGNED-PAYLOAD".to_owned(),
+        ];
+        all_args.extend_from_slice(args);
+        curl(&all_args, Some(config.as_bytes()))
+    }
+}
+
+impl std::fmt::Display for S3Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+impl CacheStore for S3Store {
+    fn get(&self, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let url = self.object_url(name);
+        let response = self.request(std::slice::from_ref(&url))?;
+        let (etag, contents) = match response.status {
+            200 => (response.etag, Some(response.body)),
+            404 => (None, None),
+            status => return Err(http_error("GET", &url, status)),
+        };
+        self.read_etags
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), etag);
+        Ok(contents)
+    }
+
+    fn put(&self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        let url = self.object_url(name);
+        let read_etag = self.read_etags.lock().unwrap().get(name).cloned();
+        // Caches that weren't read, like the one written by a job reading
+        // another branch's, still shouldn't clobber a concurrent write
+        let etag = match read_etag {
+            Some(etag) => etag,
+            None => self.current_etag(&url)?,
+        };
+        let condition = match etag {
+            Some(etag) => format!("If-Match: {etag}"),
+            None => "If-None-Match: *".to_owned(),
+        };
+
+        // The body can't go through stdin too, so it goes through a file
+        let mut upload = tempfile::Builder::new()
+            .prefix("timelord-upload-")
+            .suffix(".db")
+            .tempfile()?;
+        upload.write_all(contents)?;
+        upload.flush()?;
+        let mut args = [
+            "--request",
+            "PUT",
+            "--header",
+            "Content-Type: application/octet-stream",
+            "--header",
+            "Expect:",
+        ]
+        .map(String::from)
+        .to_vec();
+        args.push("--data-binary".to_owned());
+        args.push(format!("@{}", upload.path().display()));
+        args.push("--header".to_owned());
+        args.push(condition);
+        args.push(url.clone());
+        let response = self.request(&args)?;
+
+        match response.status {
+            200..300 => {
+                debug!("☁️ Uploaded {}", url);
+                self.read_etags
+                    .lock()
+                    .unwrap()
+                    .insert(name.to_owned(), response.etag);
+                Ok(())
+            }
+            // 409 is what S3 answers when a concurrent conditional write wins
+            412 | 409 => Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{url} was changed by another job since it was read, not overwriting it"),
+            )),
+            status => Err(http_error("PUT", &url, status)),
+        }
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        let url = format!(
+            "{}/{}?list-type=2&prefix={}",
+            self.endpoint,
+            self.bucket,
+            percent_encode(&self.prefix, false)
+        );
+        let response = self.request(std::slice::from_ref(&url))?;
+        if response.status != 200 {
+            return Err(http_error("GET", &url, response.status));
+        }
+        let listing = String::from_utf8_lossy(&response.body);
+        let mut names = listing
+            .split("<Key>")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("</Key>"))
+            .filter_map(|(key, _)| key.strip_prefix(self.prefix.as_str())?.strip_suffix(".db"))
+            .filter(|name| !name.contains('/'))
+            .map(|name| name.to_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` if
+/// `keep_slashes` is set
+fn percent_encode(s: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slashes => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Escapes a value for a quoted string in a curl config file
+fn escape_config(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,149 @@
+//! Where caches are kept: the [`CacheStore`] trait and its implementations
+
+use camino::Utf8PathBuf;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Held while a cache is locked, dropping it releases the lock
+pub type CacheLock = Box<dyn std::any::Any + Send>;
+
+/// Storage for serialized caches, looked up by cache name (see
+/// [`crate::DEFAULT_CACHE_NAME`]). The sync logic only goes through this
+/// trait, so caches can live anywhere bytes can be kept.
+///
+/// `Display` should say where the store keeps caches, for logs.
+pub trait CacheStore: std::fmt::Display + Send + Sync {
+    /// Fetches the cache called `name`, `Ok(None)` if there's no such cache
+    fn get(&self, name: &str) -> std::io::Result<Option<Vec<u8>>>;
+
+    /// Stores `contents` as the cache called `name`, replacing any previous one
+    fn put(&self, name: &str, contents: &[u8]) -> std::io::Result<()>;
+
+    /// Lists the names of every cache in the store, sorted
+    fn list(&self) -> std::io::Result<Vec<String>>;
+
+    /// Waits for exclusive access to the cache called `name`, so concurrent
+    /// jobs don't overwrite each other's caches. `Ok(None)` if the store
+    /// doesn't support locking.
+    fn lock(&self, name: &str) -> std::io::Result<Option<CacheLock>> {
+        let _ = name;
+        Ok(None)
+    }
+}
+
+/// Keeps caches as `<name>.db` files in a local directory
+#[derive(Debug, Clone)]
+pub struct FsStore {
+    dir: Utf8PathBuf,
+}
+
+impl FsStore {
+    pub fn new(dir: Utf8PathBuf) -> Self {
+        FsStore { dir }
+    }
+}
+
+impl std::fmt::Display for FsStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.dir.fmt(f)
+    }
+}
+
+impl CacheStore for FsStore {
+    fn get(&self, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match fs::read(crate::cache_file(&self.dir, name)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        // Create the directory if it doesn't exist
+        fs::create_dir_all(&self.dir)?;
+        // Written next to the cache then renamed over it, so a crash or a full
+        // disk never leaves a truncated cache behind. The suffix keeps it out
+        // of `list`.
+        let prefix = format!(".{name}-");
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix).suffix(".tmp");
+        // Temp files are private, but caches are read like any other file
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o644));
+        let mut file = builder.tempfile_in(&self.dir)?;
+        file.write_all(contents)?;
+        file.persist(crate::cache_file(&self.dir, name))
+            .map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        let entries = match self.dir.read_dir_utf8() {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.into_path();
+            if path.extension() == Some("db")
+                && let Some(name) = path.file_stem()
+            {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Locks `<name>.lock` next to the cache. The OS releases the lock if the
+    /// process dies, so there are no stale locks to clean up.
+    fn lock(&self, name: &str) -> std::io::Result<Option<CacheLock>> {
+        fs::create_dir_all(&self.dir)?;
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join(format!("{name}.lock")))?;
+        file.lock()?;
+        Ok(Some(Box::new(file)))
+    }
+}
+
+/// Keeps caches in memory, for tests
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    caches: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Display for MemoryStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("memory")
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get(&self, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.caches.lock().unwrap().get(name).cloned())
+    }
+
+    fn put(&self, name: &str, contents: &[u8]) -> std::io::Result<()> {
+        self.caches
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), contents.to_vec());
+        Ok(())
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.caches.lock().unwrap().keys().cloned().collect())
+    }
+}
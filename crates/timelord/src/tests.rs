@@ -304,3 +304,1405 @@ fn self_test() {
         "===============================================".blue()
     );
 }
+
+/// A temp dir holding an empty `source` directory, and where a `cache`
+/// directory would go. The temp dir is deleted when dropped.
+fn fixture() -> (tempfile::TempDir, Utf8PathBuf, Utf8PathBuf) {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let source_dir = Utf8PathBuf::from_path_buf(temp_dir.path().join("source")).unwrap();
+    let cache_dir = Utf8PathBuf::from_path_buf(temp_dir.path().join("cache")).unwrap();
+    std::fs::create_dir_all(&source_dir).unwrap();
+    (temp_dir, source_dir, cache_dir)
+}
+
+fn mtime(path: &camino::Utf8Path) -> std::time::SystemTime {
+    std::fs::metadata(path).unwrap().modified().unwrap()
+}
+
+fn set_mtime(path: &camino::Utf8Path, time: std::time::SystemTime) {
+    std::fs::File::open(path)
+        .unwrap()
+        .set_modified(time)
+        .unwrap();
+}
+
+fn git(dir: &camino::Utf8Path, args: &[&str]) {
+    let status = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args([
+            "-c",
+            "user.name=Timelord",
+            "-c",
+            "user.email=timelord@example.org",
+        ])
+        .args(["-c", "commit.gpgsign=false"])
+        .args(args)
+        .env("GIT_AUTHOR_DATE", "@1600000000 +0000")
+        .env("GIT_COMMITTER_DATE", "@1600000000 +0000")
+        .status()
+        .expect("Failed to run git");
+    assert!(status.success(), "git {:?} failed", args);
+}
+
+#[test]
+fn git_commit_time_fallback() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    fs::create_dir_all(source_dir.join("src")).unwrap();
+
+    // One committed file, one untracked file, one file with uncommitted changes
+    fs::write(source_dir.join("src/main.rs"), "fn main() {}").unwrap();
+    fs::write(source_dir.join("src/lib.rs"), "// lib").unwrap();
+    git(&source_dir, &["init", "-q"]);
+    git(&source_dir, &["add", "."]);
+    git(&source_dir, &["commit", "-q", "-m", "initial"]);
+    fs::write(source_dir.join("src/lib.rs"), "// lib, edited").unwrap();
+    fs::write(source_dir.join("untracked.rs"), "// untracked").unwrap();
+
+    let options = super::SyncOptions {
+        fallback: super::FallbackPolicy::GitCommitTime,
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options);
+
+    let commit_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1600000000);
+    let mtime = |path: &str| {
+        fs::metadata(source_dir.join(path))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    let source = |path: &str| {
+        report
+            .files
+            .iter()
+            .find(|f| f.path.to_string() == path)
+            .unwrap()
+            .source
+    };
+    assert_eq!(mtime("src/main.rs"), commit_time);
+    assert_eq!(source("src/main.rs"), super::TimestampSource::GitCommit);
+    assert_ne!(mtime("src/lib.rs"), commit_time);
+    assert_eq!(source("src/lib.rs"), super::TimestampSource::Checkout);
+    assert_eq!(source("untracked.rs"), super::TimestampSource::Checkout);
+
+    // A committed change keeps its checkout mtime, its commit may be older
+    // than what was built from the previous contents
+    fs::write(source_dir.join("src/main.rs"), "fn main() { todo!() }").unwrap();
+    git(&source_dir, &["commit", "-q", "-a", "-m", "edit"]);
+    let report = super::sync_with_options(source_dir.clone(), cache_dir, &options);
+    let file = report
+        .files
+        .iter()
+        .find(|f| f.path.to_string() == "src/main.rs")
+        .unwrap();
+    assert_eq!(file.dirty_reason, Some(super::DirtyReason::HashChanged));
+    assert_eq!(file.source, super::TimestampSource::Checkout);
+    assert_ne!(mtime("src/main.rs"), commit_time);
+}
+
+#[test]
+fn source_date_epoch_clamping() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+
+    let now = SystemTime::now();
+    let hours_ago = |hours: u64| now - Duration::from_secs(hours * 3600);
+    let set_mtime = |path: &str, time: SystemTime| set_mtime(&source_dir.join(path), time);
+    let mtime = |path: &str| mtime(&source_dir.join(path));
+
+    // Cache one file from before the epoch, and one from after it
+    fs::write(source_dir.join("old.rs"), "// old").unwrap();
+    fs::write(source_dir.join("recent.rs"), "// recent").unwrap();
+    set_mtime("old.rs", hours_ago(3));
+    set_mtime("recent.rs", hours_ago(1));
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // Simulate a fresh checkout, with a new file
+    set_mtime("old.rs", now);
+    set_mtime("recent.rs", now);
+    fs::write(source_dir.join("new.rs"), "// new").unwrap();
+
+    let epoch = hours_ago(2);
+    let options = super::SyncOptions {
+        source_date_epoch: Some(epoch),
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir, &options);
+
+    assert_eq!(mtime("old.rs"), hours_ago(3), "old.rs should be restored");
+    assert_eq!(mtime("recent.rs"), epoch, "recent.rs should be clamped");
+    assert_eq!(mtime("new.rs"), epoch, "new.rs should be clamped");
+    assert_eq!(
+        report.count_from(super::TimestampSource::SourceDateEpoch),
+        2
+    );
+}
+
+#[test]
+fn touch_dirty_files_past_restored_ones() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+
+    // A runner with a fast clock cached a timestamp an hour in the future
+    let future = SystemTime::now() + Duration::from_secs(3600);
+    fs::write(source_dir.join("fresh.rs"), "// fresh").unwrap();
+    fs::write(source_dir.join("dirty.rs"), "// dirty").unwrap();
+    set_mtime(&source_dir.join("fresh.rs"), future);
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // On this runner, one file changes and another one appears
+    fs::write(source_dir.join("dirty.rs"), "// dirty, edited").unwrap();
+    fs::write(source_dir.join("new.rs"), "// new").unwrap();
+
+    let options = super::SyncOptions {
+        touch_dirty: true,
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir, &options);
+
+    let sync_time = report.sync_time.expect("sync time should be reported");
+    assert!(sync_time > future, "sync time should be past restored ones");
+    assert_eq!(mtime(&source_dir.join("fresh.rs")), future);
+    assert_eq!(mtime(&source_dir.join("dirty.rs")), sync_time);
+    assert_eq!(mtime(&source_dir.join("new.rs")), sync_time);
+    assert_eq!(report.count_from(super::TimestampSource::SyncTime), 2);
+}
+
+#[test]
+fn clock_skew_clamp() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+
+    // Cache a timestamp from a runner whose clock is a day ahead
+    let main_rs = source_dir.join("main.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    set_mtime(&main_rs, SystemTime::now() + Duration::from_secs(86400));
+    super::sync(source_dir.clone(), cache_dir.clone());
+    set_mtime(&main_rs, SystemTime::now() - Duration::from_secs(60));
+
+    let options = super::SyncOptions {
+        clock_skew: super::ClockSkewPolicy::Clamp,
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir, &options);
+
+    assert_eq!(report.clock_skew.future_timestamps, 1);
+    assert!(report.clock_skew.max_ahead > Duration::from_secs(86000));
+    assert_eq!(report.files[0].source, super::TimestampSource::Now);
+    assert!(
+        mtime(&main_rs) <= SystemTime::now(),
+        "main.rs should not be left in the future"
+    );
+}
+
+#[test]
+fn directory_timestamps() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    let src = source_dir.join("src");
+    let tests = source_dir.join("tests");
+    fs::create_dir_all(&src).unwrap();
+    fs::create_dir_all(&tests).unwrap();
+    fs::write(src.join("main.rs"), "fn main() {}").unwrap();
+    fs::write(tests.join("smoke.rs"), "// smoke").unwrap();
+
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_mtime(&src, an_hour_ago);
+    set_mtime(&tests, an_hour_ago);
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // Simulate a fresh checkout, where one directory gains a file
+    set_mtime(&src, SystemTime::now());
+    fs::write(tests.join("other.rs"), "// other").unwrap();
+    let tests_mtime = mtime(&tests);
+
+    let report = super::sync(source_dir.clone(), cache_dir);
+
+    let dir_report = |path: &str| {
+        report
+            .files
+            .iter()
+            .find(|f| f.path.to_string() == path)
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(mtime(&src), an_hour_ago, "src/ should be restored");
+    assert_eq!(dir_report("src").kind, super::EntryKind::Directory);
+    assert_eq!(dir_report("src").dirty_reason, None);
+    assert_eq!(mtime(&tests), tests_mtime, "tests/ should be left alone");
+    assert_eq!(
+        dir_report("tests").dirty_reason,
+        Some(super::DirtyReason::ChildrenChanged)
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_timestamps() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    let target = source_dir.join("target.rs");
+    let link = source_dir.join("link.rs");
+    let other_link = source_dir.join("other-link.rs");
+    fs::write(&target, "// target").unwrap();
+    std::os::unix::fs::symlink("target.rs", &link).unwrap();
+    std::os::unix::fs::symlink("target.rs", &other_link).unwrap();
+    let link_mtime =
+        |path: &camino::Utf8Path| fs::symlink_metadata(path).unwrap().modified().unwrap();
+
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    super::times::set_mtime(&link, an_hour_ago).unwrap();
+    super::times::set_mtime(&other_link, an_hour_ago).unwrap();
+    let target_mtime = mtime(&target);
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // Simulate a fresh checkout, and turn one link into a file whose
+    // contents look exactly like the link's target
+    super::times::set_mtime(&link, SystemTime::now()).unwrap();
+    fs::remove_file(&other_link).unwrap();
+    fs::write(&other_link, "target.rs").unwrap();
+
+    let report = super::sync(source_dir.clone(), cache_dir);
+
+    assert_eq!(link_mtime(&link), an_hour_ago, "link should be restored");
+    assert_eq!(mtime(&target), target_mtime, "target should be untouched");
+    let other = report
+        .files
+        .iter()
+        .find(|f| f.path.to_string() == "other-link.rs")
+        .unwrap();
+    assert_eq!(other.kind, super::EntryKind::File);
+    assert_eq!(other.dirty_reason, Some(super::DirtyReason::KindChanged));
+}
+
+#[cfg(unix)]
+#[test]
+fn set_mtime_without_opening() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = Utf8PathBuf::from_path_buf(temp_dir.path().to_owned()).unwrap();
+
+    // Owners can set timestamps on files they can't read or write
+    let locked = dir.join("locked.rs");
+    fs::write(&locked, "// locked").unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    super::times::set_mtime(&locked, an_hour_ago).unwrap();
+    assert_eq!(mtime(&locked), an_hour_ago);
+
+    // Failures carry the errno
+    let error = super::times::set_mtime(&dir.join("missing.rs"), an_hour_ago).unwrap_err();
+    let error = super::RestoreError::from(error);
+    assert_eq!(error.errno, Some(libc::ENOENT));
+}
+
+#[cfg(unix)]
+#[test]
+fn sync_unreadable_entries() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Root can read anything
+    if unsafe { libc::geteuid() } == 0 {
+        return;
+    }
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    fs::create_dir_all(source_dir.join("locked_dir")).unwrap();
+    fs::write(source_dir.join("locked_dir/child.rs"), "// child").unwrap();
+    fs::write(source_dir.join("locked.rs"), "// locked").unwrap();
+    fs::write(source_dir.join("open.rs"), "// open").unwrap();
+    fs::set_permissions(
+        source_dir.join("locked.rs"),
+        fs::Permissions::from_mode(0o000),
+    )
+    .unwrap();
+    let report = super::sync(source_dir.clone(), cache_dir.clone());
+    let locked = report
+        .files
+        .iter()
+        .find(|f| f.path.0 == "locked.rs")
+        .unwrap();
+    assert_eq!(locked.dirty_reason, Some(super::DirtyReason::Unreadable));
+
+    fs::set_permissions(
+        source_dir.join("locked_dir"),
+        fs::Permissions::from_mode(0o000),
+    )
+    .unwrap();
+    let options = super::SyncOptions {
+        touch_dirty: true,
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options);
+    let status = |path: &str| {
+        let file = report.files.iter().find(|f| f.path.0 == path).unwrap();
+        (file.dirty_reason, file.source, file.error.is_some())
+    };
+    // Unreadable files still get their mtime set, by path
+    assert_eq!(
+        status("locked.rs"),
+        (
+            Some(super::DirtyReason::Unreadable),
+            super::TimestampSource::SyncTime,
+            false
+        )
+    );
+    assert_eq!(
+        status("locked_dir"),
+        (
+            Some(super::DirtyReason::Unreadable),
+            super::TimestampSource::Checkout,
+            false
+        )
+    );
+    assert_eq!(
+        status("open.rs"),
+        (None, super::TimestampSource::Cache, false)
+    );
+    // The directory's children weren't seen, but they weren't deleted either
+    assert!(report.deleted.is_empty());
+    let cache =
+        super::read_cache(&super::cache_file(&cache_dir, super::DEFAULT_CACHE_NAME)).unwrap();
+    assert!(!cache.entries.keys().any(|path| path.0 == "locked.rs"));
+
+    fs::set_permissions(
+        source_dir.join("locked_dir"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+}
+
+#[test]
+fn mtime_granularity() {
+    use super::times::granularity_of;
+    use std::time::{Duration, SystemTime};
+
+    let set = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_001, 123_456_789);
+    let at = |secs, nanos| SystemTime::UNIX_EPOCH + Duration::new(secs, nanos);
+    assert_eq!(granularity_of(set, set), Duration::from_nanos(1));
+    assert_eq!(
+        granularity_of(set, at(1_000_000_001, 123_456_000)),
+        Duration::from_micros(1)
+    );
+    assert_eq!(
+        granularity_of(set, at(1_000_000_001, 0)),
+        Duration::from_secs(1)
+    );
+    assert_eq!(
+        granularity_of(set, at(1_000_000_000, 0)),
+        Duration::from_secs(2)
+    );
+    // Some filesystems round instead of truncating
+    assert_eq!(
+        granularity_of(set, at(1_000_000_001, 124_000_000)),
+        Duration::from_millis(1)
+    );
+
+    // Whatever the filesystem under the temp dir does, probing should work
+    // and restored timestamps should round-trip through the cache
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    std::fs::write(source_dir.join("main.rs"), "fn main() {}").unwrap();
+    super::sync(source_dir.clone(), cache_dir.clone());
+    set_mtime(&source_dir.join("main.rs"), SystemTime::now());
+
+    let report = super::sync(source_dir.clone(), cache_dir);
+    assert!(report.precision.granularity.is_some());
+    assert_eq!(report.precision.mismatched, 0);
+
+    // Without a probed granularity, truncation to a known one still counts
+    // as normalized, as if the filesystem stored whole seconds
+    let main_rs = source_dir.join("main.rs");
+    set_mtime(&main_rs, at(1_000_000_001, 0));
+    let read_back = super::ReadBack::new(None);
+    assert_eq!(
+        read_back.mtime_after_set(&main_rs, set),
+        at(1_000_000_001, 0)
+    );
+    let precision = read_back.report();
+    assert_eq!((precision.normalized, precision.mismatched), (1, 0));
+    assert_eq!(precision.granularity, Some(Duration::from_secs(1)));
+    // An hour off is still a mismatch
+    read_back.mtime_after_set(&main_rs, set + Duration::from_secs(3600));
+    assert_eq!(read_back.report().mismatched, 1);
+}
+
+#[test]
+fn preserve_atime() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    let main_rs = source_dir.join("main.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+
+    let two_hours_ago = SystemTime::now() - Duration::from_secs(7200);
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    super::times::set_times(&main_rs, an_hour_ago, Some(two_hours_ago)).unwrap();
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // Simulate a fresh checkout
+    let now = SystemTime::now();
+    super::times::set_times(&main_rs, now, Some(now)).unwrap();
+
+    let options = super::SyncOptions {
+        preserve_atime: true,
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir, cache_dir, &options);
+
+    let metadata = fs::metadata(&main_rs).unwrap();
+    assert_eq!(metadata.modified().unwrap(), an_hour_ago);
+    assert_eq!(metadata.accessed().unwrap(), two_hours_ago);
+    assert_eq!(report.atimes_restored, Some(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn mode_changes_are_dirty() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    fs::create_dir_all(source_dir.join("src")).unwrap();
+    let script = source_dir.join("build.sh");
+    fs::write(&script, "#!/bin/sh\necho hi\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o644)).unwrap();
+    set_mtime(&script, SystemTime::now() - Duration::from_secs(3600));
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // A different umask only changes the group and other bits, and directory
+    // modes don't count at all
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o664)).unwrap();
+    fs::set_permissions(source_dir.join("src"), fs::Permissions::from_mode(0o775)).unwrap();
+    let report = super::sync(source_dir.clone(), cache_dir.clone());
+    assert!(report.files.iter().all(|f| f.dirty_reason.is_none()));
+
+    // chmod +x doesn't change contents, but the file still changed
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let now = SystemTime::now();
+    set_mtime(&script, now);
+
+    let report = super::sync(source_dir, cache_dir);
+
+    assert_eq!(mtime(&script), now, "build.sh should not be restored");
+    assert_eq!(
+        report.files[0].dirty_reason,
+        Some(super::DirtyReason::ModeChanged)
+    );
+}
+
+#[test]
+fn deleted_files() {
+    use std::fs;
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    fs::create_dir_all(source_dir.join("src")).unwrap();
+    fs::write(source_dir.join("src/main.rs"), "fn main() {}").unwrap();
+    fs::write(source_dir.join("src/gone.rs"), "// gone").unwrap();
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    fs::remove_file(source_dir.join("src/gone.rs")).unwrap();
+    let report = super::sync(source_dir, cache_dir);
+
+    let deleted: Vec<String> = report
+        .deleted
+        .iter()
+        .map(|entry| entry.path.to_string())
+        .collect();
+    assert_eq!(deleted, ["src/gone.rs"]);
+}
+
+#[test]
+fn retain_missing_entries() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    let main_rs = source_dir.join("main.rs");
+    let outside_rs = source_dir.join("outside.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    fs::write(&outside_rs, "// outside the sparse cone").unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_mtime(&outside_rs, an_hour_ago);
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // A sparse checkout doesn't have outside.rs...
+    fs::remove_file(&outside_rs).unwrap();
+    let options = super::SyncOptions {
+        retain_missing: Some(Duration::from_secs(86400)),
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options);
+    assert_eq!(report.deleted.len(), 1);
+    assert!(report.retained.is_empty());
+    // ...which isn't reported as deleted again by the next sparse sync
+    let report = super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options);
+    assert!(report.deleted.is_empty());
+    assert_eq!(report.retained.len(), 1);
+    assert_eq!(report.retained[0].path.0, "outside.rs");
+
+    // ...but the next full checkout still gets its timestamp back
+    fs::write(&outside_rs, "// outside the sparse cone").unwrap();
+    super::sync(source_dir.clone(), cache_dir.clone());
+    assert_eq!(mtime(&outside_rs), an_hour_ago);
+
+    // Without it, the entry is gone
+    fs::remove_file(&outside_rs).unwrap();
+    super::sync(source_dir.clone(), cache_dir.clone());
+    fs::write(&outside_rs, "// outside the sparse cone").unwrap();
+    let report = super::sync(source_dir, cache_dir);
+    assert_ne!(mtime(&outside_rs), an_hour_ago);
+    assert_eq!(report.files[1].dirty_reason, Some(super::DirtyReason::New));
+}
+
+#[test]
+fn rename_detection() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    for (name, contents) in [
+        ("unique.rs", "// unique"),
+        ("copy-a.rs", "// copy"),
+        ("copy-b.rs", "// copy"),
+    ] {
+        fs::write(source_dir.join(name), contents).unwrap();
+        set_mtime(&source_dir.join(name), an_hour_ago);
+    }
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // One unambiguous move, one where two identical files disappeared
+    fs::rename(source_dir.join("unique.rs"), source_dir.join("moved.rs")).unwrap();
+    fs::rename(source_dir.join("copy-a.rs"), source_dir.join("copy-c.rs")).unwrap();
+    fs::remove_file(source_dir.join("copy-b.rs")).unwrap();
+    set_mtime(&source_dir.join("moved.rs"), SystemTime::now());
+    set_mtime(&source_dir.join("copy-c.rs"), SystemTime::now());
+
+    let options = super::SyncOptions {
+        detect_renames: true,
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir, &options);
+
+    let file = |path: &str| report.files.iter().find(|f| f.path.to_string() == path);
+    let moved = file("moved.rs").unwrap();
+    assert_eq!(moved.source, super::TimestampSource::Renamed);
+    assert_eq!(
+        moved.renamed_from.as_ref().unwrap().to_string(),
+        "unique.rs"
+    );
+    assert_eq!(mtime(&source_dir.join("moved.rs")), an_hour_ago);
+    let ambiguous = file("copy-c.rs").unwrap();
+    assert_eq!(ambiguous.dirty_reason, Some(super::DirtyReason::New));
+    assert_ne!(mtime(&source_dir.join("copy-c.rs")), an_hour_ago);
+}
+
+#[test]
+fn gc_prunes_by_runs() {
+    use std::fs;
+    use std::time::Duration;
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    fs::write(source_dir.join("main.rs"), "fn main() {}").unwrap();
+    fs::write(source_dir.join("outside.rs"), "// outside the sparse cone").unwrap();
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    // Keep outside.rs around for two sparse runs
+    fs::remove_file(source_dir.join("outside.rs")).unwrap();
+    let options = super::SyncOptions {
+        retain_missing: Some(Duration::from_secs(86400)),
+        ..Default::default()
+    };
+    super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options);
+    super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options);
+
+    let cache = super::read_cache(&cache_dir.join("timelord.db")).unwrap();
+    assert_eq!(cache.run, 3);
+    assert_eq!(cache.entries.len(), 2);
+
+    // Zero would prune everything, it's ignored
+    let report = super::gc(cache_dir.clone(), super::DEFAULT_CACHE_NAME, None, Some(0)).unwrap();
+    assert_eq!((report.pruned, report.kept), (0, 2));
+
+    // Not seen during the last three runs: kept
+    let report = super::gc(cache_dir.clone(), super::DEFAULT_CACHE_NAME, None, Some(3)).unwrap();
+    assert_eq!((report.pruned, report.kept), (0, 2));
+    assert_eq!(report.bytes_before, report.bytes_after);
+
+    // Not seen during the last two runs: pruned
+    let report = super::gc(cache_dir.clone(), super::DEFAULT_CACHE_NAME, None, Some(2)).unwrap();
+    assert_eq!((report.pruned, report.kept), (1, 1));
+    assert!(report.bytes_after < report.bytes_before);
+
+    // Everything was seen just now
+    let report = super::gc(
+        cache_dir,
+        super::DEFAULT_CACHE_NAME,
+        Some(Duration::from_secs(3600)),
+        None,
+    )
+    .unwrap();
+    assert_eq!((report.pruned, report.kept), (0, 1));
+}
+
+#[test]
+fn named_caches() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let debug_dir = Utf8PathBuf::from_path_buf(temp_dir.path().join("debug")).unwrap();
+    let release_dir = Utf8PathBuf::from_path_buf(temp_dir.path().join("release")).unwrap();
+    let cache_dir = Utf8PathBuf::from_path_buf(temp_dir.path().join("cache")).unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    let a_day_ago = SystemTime::now() - Duration::from_secs(86400);
+    for (dir, mtime) in [(&debug_dir, an_hour_ago), (&release_dir, a_day_ago)] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("main.rs"), "fn main() {}").unwrap();
+        set_mtime(&dir.join("main.rs"), mtime);
+    }
+
+    let sync = |source_dir: &Utf8PathBuf, name: &str| {
+        let options = super::SyncOptions {
+            cache_name: Some(name.to_owned()),
+            ..Default::default()
+        };
+        super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options)
+    };
+    sync(&debug_dir, "debug");
+    sync(&release_dir, "release");
+    assert!(cache_dir.join("debug.db").exists());
+    assert!(cache_dir.join("release.db").exists());
+    assert!(!cache_dir.join("timelord.db").exists());
+    // Caches are renamed into place, no temp files are left behind
+    let mut files: Vec<String> = fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        ["debug.db", "debug.lock", "release.db", "release.lock"]
+    );
+
+    // Each checkout gets its own timestamps back
+    for dir in [&debug_dir, &release_dir] {
+        fs::write(dir.join("main.rs"), "fn main() {}").unwrap();
+    }
+    sync(&debug_dir, "debug");
+    sync(&release_dir, "release");
+    assert_eq!(mtime(&debug_dir.join("main.rs")), an_hour_ago);
+    assert_eq!(mtime(&release_dir.join("main.rs")), a_day_ago);
+
+    let cache = super::read_cache(&super::cache_file(&cache_dir, "release")).unwrap();
+    assert_eq!(
+        cache.absolute_path,
+        release_dir.canonicalize_utf8().unwrap()
+    );
+}
+
+#[test]
+fn read_through_fallback_chain() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    let main_rs = source_dir.join("main.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_mtime(&main_rs, an_hour_ago);
+
+    let sync = |name: &str| {
+        let options = super::SyncOptions {
+            cache_name: Some(name.to_owned()),
+            read_from: vec!["pr-123".to_owned(), "main".to_owned()],
+            ..Default::default()
+        };
+        super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options)
+    };
+
+    // The main branch made a cache, the PR branch hasn't yet
+    super::sync_with_options(
+        source_dir.clone(),
+        cache_dir.clone(),
+        &super::SyncOptions {
+            cache_name: Some("main".to_owned()),
+            ..Default::default()
+        },
+    );
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let report = sync("pr-123");
+    assert_eq!(mtime(&main_rs), an_hour_ago);
+    assert_eq!(report.cache_source.as_deref(), Some("main"));
+
+    // Now it has, and the main branch's cache was left alone
+    let main_cache = super::read_cache(&super::cache_file(&cache_dir, "main")).unwrap();
+    assert_eq!(main_cache.run, 1);
+    let pr_cache = super::read_cache(&super::cache_file(&cache_dir, "pr-123")).unwrap();
+    assert_eq!(pr_cache.read_from.as_deref(), Some("main"));
+    let report = sync("pr-123");
+    assert_eq!(report.cache_source.as_deref(), Some("pr-123"));
+
+    // Nothing valid to read from
+    fs::remove_file(super::cache_file(&cache_dir, "pr-123")).unwrap();
+    fs::remove_file(super::cache_file(&cache_dir, "main")).unwrap();
+    let report = sync("pr-123");
+    assert_eq!(report.cache_source, None);
+}
+
+#[test]
+fn read_only_cache() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    let main_rs = source_dir.join("main.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_mtime(&main_rs, an_hour_ago);
+    super::sync(source_dir.clone(), cache_dir.clone());
+    let cache_file = cache_dir.join("timelord.db");
+    let saved = fs::read(&cache_file).unwrap();
+
+    // Restores as usual, but leaves the cache alone
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    fs::write(source_dir.join("new.rs"), "// new").unwrap();
+    let options = super::SyncOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir.clone(), cache_dir.clone(), &options);
+    assert_eq!(mtime(&main_rs), an_hour_ago);
+    assert!(report.save_error.is_none());
+    assert_eq!(fs::read(&cache_file).unwrap(), saved);
+
+    // A cache directory that can't be written to is reported, not a panic
+    let blocked_dir = cache_dir.join("timelord.db").join("nested");
+    let report = super::sync(source_dir, blocked_dir);
+    assert!(report.save_error.is_some());
+}
+
+#[test]
+fn merge_shard_caches() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let shard_a = Utf8PathBuf::from_path_buf(temp_dir.path().join("a")).unwrap();
+    let shard_b = Utf8PathBuf::from_path_buf(temp_dir.path().join("b")).unwrap();
+    let cache_a = Utf8PathBuf::from_path_buf(temp_dir.path().join("cache-a")).unwrap();
+    let cache_b = Utf8PathBuf::from_path_buf(temp_dir.path().join("cache-b")).unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    let a_day_ago = SystemTime::now() - Duration::from_secs(86400);
+    let write = |dir: &Utf8PathBuf, name: &str, contents: &str, mtime: SystemTime| {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(name), contents).unwrap();
+        set_mtime(&dir.join(name), mtime);
+    };
+    write(&shard_a, "only-a.rs", "// a", an_hour_ago);
+    write(&shard_a, "common.rs", "// common", an_hour_ago);
+    write(&shard_a, "conflict.rs", "// from a", a_day_ago);
+    write(&shard_b, "only-b.rs", "// b", an_hour_ago);
+    write(&shard_b, "common.rs", "// common", a_day_ago);
+    write(&shard_b, "conflict.rs", "// from b", an_hour_ago);
+    super::sync(shard_a, cache_a.clone());
+    // Shard b is crawled last
+    super::sync(shard_b, cache_b.clone());
+
+    let inputs = vec![cache_a.join("timelord.db"), cache_b.join("timelord.db")];
+    let merged_file = Utf8PathBuf::from_path_buf(temp_dir.path().join("merged.db")).unwrap();
+    let timestamp = |cache: &super::Cache, name: &str| {
+        cache.entries[&super::RelativePath(Utf8PathBuf::from(name))].timestamp
+    };
+
+    assert_eq!(
+        super::merge(&inputs, &merged_file, super::MergePolicy::NewestCrawl),
+        Some(4)
+    );
+    let merged = super::read_cache(&merged_file).unwrap();
+    assert_eq!(timestamp(&merged, "only-a.rs"), an_hour_ago);
+    assert_eq!(timestamp(&merged, "only-b.rs"), an_hour_ago);
+    // Same contents: the oldest timestamp is kept
+    assert_eq!(timestamp(&merged, "common.rs"), a_day_ago);
+    // Different contents: the newest crawl wins
+    assert_eq!(timestamp(&merged, "conflict.rs"), an_hour_ago);
+
+    super::merge(&inputs, &merged_file, super::MergePolicy::OldestTimestamp).unwrap();
+    let merged = super::read_cache(&merged_file).unwrap();
+    assert_eq!(timestamp(&merged, "conflict.rs"), a_day_ago);
+
+    // The output is saved like any cache, so it has to look like one
+    let merged_bin = Utf8PathBuf::from_path_buf(temp_dir.path().join("merged.bin")).unwrap();
+    assert_eq!(super::merge(&inputs, &merged_bin, Default::default()), None);
+    assert!(!merged_bin.exists());
+
+    // Invalid inputs are skipped
+    let missing = Utf8PathBuf::from_path_buf(temp_dir.path().join("missing.db")).unwrap();
+    assert_eq!(
+        super::merge(
+            std::slice::from_ref(&missing),
+            &merged_file,
+            Default::default()
+        ),
+        None
+    );
+    assert_eq!(
+        super::merge(
+            &[missing, inputs[0].clone()],
+            &merged_file,
+            Default::default()
+        ),
+        Some(3)
+    );
+}
+
+#[test]
+fn merge_shards_at_different_runs() {
+    use std::fs;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let shard_a = Utf8PathBuf::from_path_buf(temp_dir.path().join("a")).unwrap();
+    let shard_b = Utf8PathBuf::from_path_buf(temp_dir.path().join("b")).unwrap();
+    let cache_a = Utf8PathBuf::from_path_buf(temp_dir.path().join("cache-a")).unwrap();
+    let cache_b = Utf8PathBuf::from_path_buf(temp_dir.path().join("cache-b")).unwrap();
+    let merged_dir = Utf8PathBuf::from_path_buf(temp_dir.path().join("merged")).unwrap();
+    for (dir, name) in [(&shard_a, "only-a.rs"), (&shard_b, "only-b.rs")] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(name), "// shard").unwrap();
+    }
+    // Shard a is at run 3, shard b at run 1, both saw their files last time
+    super::sync(shard_b.clone(), cache_b.clone());
+    for _ in 0..3 {
+        super::sync(shard_a.clone(), cache_a.clone());
+    }
+
+    let inputs = vec![cache_a.join("timelord.db"), cache_b.join("timelord.db")];
+    super::merge(&inputs, &merged_dir.join("timelord.db"), Default::default()).unwrap();
+    let merged = super::read_cache(&merged_dir.join("timelord.db")).unwrap();
+    assert_eq!(merged.run, 3);
+    let only_b = super::RelativePath(Utf8PathBuf::from("only-b.rs"));
+    assert_eq!(merged.entries[&only_b].last_seen_run, 3);
+
+    // Both were seen during the last run
+    let report = super::gc(merged_dir.clone(), super::DEFAULT_CACHE_NAME, None, Some(1)).unwrap();
+    assert_eq!((report.pruned, report.kept), (0, 2));
+
+    // So deleting one is a deletion, not a carried over entry
+    fs::remove_file(shard_b.join("only-b.rs")).unwrap();
+    let report = super::sync(shard_b, merged_dir);
+    assert!(report.deleted.iter().any(|entry| entry.path == only_b));
+    assert!(report.retained.is_empty());
+}
+
+#[test]
+fn memory_store() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, _) = fixture();
+    let main_rs = source_dir.join("main.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_mtime(&main_rs, an_hour_ago);
+
+    let store = super::MemoryStore::new();
+    super::sync_with_store(source_dir.clone(), &store, &Default::default());
+    assert_eq!(
+        super::CacheStore::list(&store).unwrap(),
+        vec![super::DEFAULT_CACHE_NAME.to_owned()]
+    );
+
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let report = super::sync_with_store(source_dir, &store, &Default::default());
+    assert_eq!(mtime(&main_rs), an_hour_ago);
+    assert_eq!(report.fresh, 1);
+    let cache = super::read_cache_from(&store, super::DEFAULT_CACHE_NAME).unwrap();
+    assert_eq!(cache.run, 2);
+}
+
+/// Stands in for an nginx-style HTTP cache or an S3 bucket: GET, HEAD and PUT
+/// by path with ETags, conditional PUTs, and `list-type=2` listings
+struct StandInCache {
+    url: String,
+    /// How many responses carried a cache
+    downloads: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// The headers of every request, with lowercase names
+    requests: std::sync::Arc<std::sync::Mutex<Vec<std::collections::HashMap<String, String>>>>,
+}
+
+fn spawn_http_cache() -> StandInCache {
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let cache = StandInCache {
+        url: format!("http://{}", listener.local_addr().unwrap()),
+        downloads: Arc::new(AtomicUsize::new(0)),
+        requests: Arc::new(Mutex::new(Vec::new())),
+    };
+    let downloads = cache.downloads.clone();
+    let requests = cache.requests.clone();
+    std::thread::spawn(move || {
+        let mut files: BTreeMap<String, (Vec<u8>, String)> = BTreeMap::new();
+        for (version, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut parts = request_line.split_whitespace();
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap().to_owned());
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let Some((key, value)) = line.trim_end().split_once(':') else {
+                    break;
+                };
+                headers.insert(key.to_ascii_lowercase(), value.trim().to_owned());
+            }
+            let content_length = headers
+                .get("content-length")
+                .map_or(0, |length| length.parse().unwrap());
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let if_match = headers.get("if-match").cloned();
+            let if_none_match = headers.get("if-none-match").cloned();
+            requests.lock().unwrap().push(headers);
+
+            let current = files.get(&path);
+            let (status, etag, body) = match (method, path.split_once("?list-type=2&prefix=")) {
+                ("GET", Some((bucket, prefix))) => {
+                    let prefix = format!("{}/{}", bucket, prefix.replace("%2F", "/"));
+                    let keys = files
+                        .keys()
+                        .filter_map(|path| path.strip_prefix(&format!("{bucket}/")))
+                        .filter(|key| format!("{bucket}/{key}").starts_with(&prefix))
+                        .map(|key| format!("<Contents><Key>{key}</Key></Contents>"))
+                        .collect::<String>();
+                    let listing = format!("<ListBucketResult>{keys}</ListBucketResult>");
+                    ("200 OK", None, listing.into_bytes())
+                }
+                ("PUT", _)
+                    if if_match.is_some_and(|etag| Some(&etag) != current.map(|(_, e)| e))
+                        || (if_none_match.as_deref() == Some("*") && current.is_some()) =>
+                {
+                    ("412 Precondition Failed", None, Vec::new())
+                }
+                ("PUT", _) => {
+                    let etag = format!("\"v{version}\"");
+                    files.insert(path, (body, etag.clone()));
+                    ("201 Created", Some(etag), Vec::new())
+                }
+                ("GET", _) => match current {
+                    Some((_, etag)) if if_none_match.as_ref() == Some(etag) => {
+                        ("304 Not Modified", Some(etag.clone()), Vec::new())
+                    }
+                    Some((contents, etag)) => {
+                        downloads.fetch_add(1, Ordering::SeqCst);
+                        ("200 OK", Some(etag.clone()), contents.clone())
+                    }
+                    None => ("404 Not Found", None, Vec::new()),
+                },
+                ("HEAD", _) => match current {
+                    Some((_, etag)) => ("200 OK", Some(etag.clone()), Vec::new()),
+                    None => ("404 Not Found", None, Vec::new()),
+                },
+                _ => ("405 Method Not Allowed", None, Vec::new()),
+            };
+            let mut response = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                body.len()
+            );
+            if let Some(etag) = etag {
+                response.push_str(&format!("ETag: {etag}\r\n"));
+            }
+            response.push_str("\r\n");
+            stream.write_all(response.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    cache
+}
+
+#[test]
+fn http_store() {
+    use std::fs;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, SystemTime};
+
+    let (temp_dir, source_dir, _) = fixture();
+    let mirror_dir = Utf8PathBuf::from_path_buf(temp_dir.path().join("mirror")).unwrap();
+    let main_rs = source_dir.join("main.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_mtime(&main_rs, an_hour_ago);
+
+    let cache = spawn_http_cache();
+    let url = &cache.url;
+    let template = format!("{url}/{{repo}}/{{name}}.db");
+    let vars = [("repo".to_owned(), "timelord".to_owned())].into();
+    assert!(
+        super::HttpStore::new(format!("{url}/{{branch}}/timelord.db"), Default::default()).is_err()
+    );
+    let store = super::HttpStore::new(template, vars)
+        .unwrap()
+        .with_mirror(mirror_dir);
+
+    // Nothing on the server yet, so the first sync uploads a cache
+    let report = super::sync_with_store(source_dir.clone(), &store, &Default::default());
+    assert_eq!(report.cache_source, None);
+    assert!(report.save_error.is_none());
+
+    // The local copy is still current, so it isn't downloaded again
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let report = super::sync_with_store(source_dir.clone(), &store, &Default::default());
+    assert_eq!(mtime(&main_rs), an_hour_ago);
+    assert_eq!(
+        report.cache_source.as_deref(),
+        Some(super::DEFAULT_CACHE_NAME)
+    );
+    assert_eq!(cache.downloads.load(Ordering::SeqCst), 0);
+
+    // Without one, it is
+    let store =
+        super::HttpStore::new(format!("{url}/timelord/{{name}}.db"), Default::default()).unwrap();
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    super::sync_with_store(source_dir, &store, &Default::default());
+    assert_eq!(mtime(&main_rs), an_hour_ago);
+    assert_eq!(cache.downloads.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn curl_headers_through_a_proxy() {
+    // What `--dump-header` writes for an HTTPS request through a proxy that
+    // redirects once
+    let headers = "HTTP/1.1 200 Connection established\r\n\r\n\
+        HTTP/1.1 302 Found\r\nLocation: /elsewhere\r\nETag: \"redirect\"\r\n\r\n\
+        HTTP/1.1 200 OK\r\nContent-Length: 4\r\netag: \"final\"\r\n\r\n";
+    assert_eq!(
+        super::http::final_etag(headers.as_bytes()).as_deref(),
+        Some("\"final\"")
+    );
+    assert_eq!(
+        super::http::final_etag(b"HTTP/1.1 404 Not Found\r\n\r\n"),
+        None
+    );
+}
+
+#[test]
+fn s3_store() {
+    use super::CacheStore;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (_temp_dir, source_dir, _) = fixture();
+    let main_rs = source_dir.join("main.rs");
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_mtime(&main_rs, an_hour_ago);
+
+    let cache = spawn_http_cache();
+    let credentials = super::S3Credentials {
+        access_key_id: "AKIDEXAMPLE".to_owned(),
+        secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+        session_token: Some("session".to_owned()),
+    };
+    let store = || {
+        super::S3Store::new("bucket", "eu-west-3", credentials.clone())
+            .with_endpoint(&cache.url)
+            .with_prefix("timelord/main/")
+    };
+
+    let job = store();
+    super::sync_with_store(source_dir.clone(), &job, &Default::default());
+    fs::write(&main_rs, "fn main() {}").unwrap();
+    let report = super::sync_with_store(source_dir.clone(), &job, &Default::default());
+    assert_eq!(mtime(&main_rs), an_hour_ago);
+    assert!(report.save_error.is_none());
+    assert_eq!(
+        job.list().unwrap(),
+        vec![super::DEFAULT_CACHE_NAME.to_owned()]
+    );
+
+    // Requests are signed, without the secret key ever being sent
+    for headers in cache.requests.lock().unwrap().iter() {
+        let authorization = &headers["authorization"];
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(authorization.contains("/eu-west-3/s3/aws4_request"));
+        assert!(!authorization.contains("wJalrXUtnFEMI"));
+        assert_eq!(headers["x-amz-security-token"], "session");
+        assert_eq!(headers["x-amz-content-sha256"], "UNSIGNED-PAYLOAD");
+    }
+
+    // Two jobs read the same cache, the one that saves last doesn't clobber
+    // the other's
+    let (first, second) = (store(), store());
+    first.get(super::DEFAULT_CACHE_NAME).unwrap();
+    second.get(super::DEFAULT_CACHE_NAME).unwrap();
+    let report = super::sync_with_store(source_dir.clone(), &first, &Default::default());
+    assert!(report.save_error.is_none());
+    let error = second.put(super::DEFAULT_CACHE_NAME, b"stale").unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+
+    // Neither do jobs that found no cache
+    let (first, second) = (store().with_prefix("pr/"), store().with_prefix("pr/"));
+    assert_eq!(first.get("pr-123").unwrap(), None);
+    assert_eq!(second.get("pr-123").unwrap(), None);
+    first.put("pr-123", b"first").unwrap();
+    assert!(second.put("pr-123", b"second").is_err());
+    assert_eq!(
+        second.get("pr-123").unwrap().as_deref(),
+        Some(&b"first"[..])
+    );
+
+    // Nor jobs writing a cache they didn't read, like one reading the main
+    // branch's cache and writing its own: their writes are still conditional
+    let unread = store().with_prefix("pr/");
+    unread.put("pr-123", b"third").unwrap();
+    let requests = cache.requests.lock().unwrap();
+    let put = requests.last().unwrap();
+    assert!(put.contains_key("if-match"));
+    assert!(!put.contains_key("if-none-match"));
+    drop(requests);
+    unread.put("pr-456", b"new").unwrap();
+    let requests = cache.requests.lock().unwrap();
+    assert_eq!(requests.last().unwrap()["if-none-match"], "*");
+}
+
+#[test]
+fn cache_key() {
+    use std::fs;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let source_dir = Utf8PathBuf::from_path_buf(temp_dir.path().to_owned()).unwrap();
+    fs::write(source_dir.join("Cargo.lock"), "version = 4").unwrap();
+    fs::write(source_dir.join("rust-toolchain.toml"), "[toolchain]").unwrap();
+    let options = super::KeyOptions {
+        repo: Some("fasterthanlime/timelord".to_owned()),
+        branch: Some("feature/keys".to_owned()),
+        toolchain: Some("1.85.0".to_owned()),
+        ..Default::default()
+    };
+
+    let key = super::cache_key(&source_dir, &options).unwrap();
+    assert_eq!(
+        key.restore_keys,
+        vec![
+            "timelord-fasterthanlime_timelord-1.85.0-feature_keys-".to_owned(),
+            "timelord-fasterthanlime_timelord-1.85.0-".to_owned(),
+        ]
+    );
+    assert!(key.key.starts_with(&key.restore_keys[0]));
+    assert_eq!(super::cache_key(&source_dir, &options).unwrap(), key);
+
+    // Cargo.lock goes into the key by default
+    fs::write(source_dir.join("Cargo.lock"), "version = 3").unwrap();
+    let changed = super::cache_key(&source_dir, &options).unwrap();
+    assert_ne!(changed.key, key.key);
+    assert_eq!(changed.restore_keys, key.restore_keys);
+
+    // Unless other files are picked
+    let with_files = |files: &[&str]| super::KeyOptions {
+        files: files.iter().map(Utf8PathBuf::from).collect(),
+        ..options.clone()
+    };
+    let toolchain_only =
+        super::cache_key(&source_dir, &with_files(&["rust-toolchain.toml"])).unwrap();
+    fs::write(source_dir.join("Cargo.lock"), "version = 4").unwrap();
+    assert_eq!(
+        super::cache_key(&source_dir, &with_files(&["rust-toolchain.toml"])).unwrap(),
+        toolchain_only
+    );
+    assert!(super::cache_key(&source_dir, &with_files(&["missing.lock"])).is_err());
+}
+
+#[test]
+fn machine_readable_reports() {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    let (temp_dir, source_dir, cache_dir) = fixture();
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    for name in ["fresh.rs", "changed.rs", "gone.rs"] {
+        fs::write(source_dir.join(name), "// before").unwrap();
+        set_mtime(&source_dir.join(name), an_hour_ago);
+    }
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    fs::write(source_dir.join("fresh.rs"), "// before").unwrap();
+    fs::write(
+        source_dir.join("changed.rs"),
+        "// after, with \"quotes\", and commas",
+    )
+    .unwrap();
+    fs::write(source_dir.join("new,file.rs"), "// new").unwrap();
+    fs::remove_file(source_dir.join("gone.rs")).unwrap();
+    let report = super::sync(source_dir, cache_dir);
+
+    let report_file = Utf8PathBuf::from_path_buf(temp_dir.path().join("report")).unwrap();
+    super::write_report(&report, &report_file, super::ReportFormat::Ndjson).unwrap();
+    let ndjson = fs::read_to_string(&report_file).unwrap();
+    let lines: Vec<&str> = ndjson.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with(
+        r#"{"path":"changed.rs","kind":"file","status":"dirty","dirty_reason":"hash_changed","source":"checkout","hash":""#
+    ));
+    assert!(lines[1].starts_with(
+        r#"{"path":"fresh.rs","kind":"file","status":"fresh","dirty_reason":null,"source":"cache","#
+    ));
+    assert!(lines[1].contains(r#""size":9,"#));
+    assert!(lines[2].contains(r#""status":"dirty","dirty_reason":"new""#));
+    assert!(lines[2].contains(r#""cached_timestamp":null"#));
+    assert_eq!(
+        lines[3],
+        r#"{"path":"gone.rs","kind":"file","status":"deleted","dirty_reason":null,"source":null,"hash":null,"size":null,"cached_timestamp":null,"checkout_timestamp":null,"new_timestamp":null,"renamed_from":null,"error":null}"#
+    );
+
+    super::write_report(&report, &report_file, super::ReportFormat::Json).unwrap();
+    let json = fs::read_to_string(&report_file).unwrap();
+    assert!(json.starts_with("[\n  {\"path\":\"changed.rs\""));
+    assert!(json.ends_with("\"error\":null}\n]\n"));
+    assert_eq!(json.lines().count(), 6);
+    let parsed: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed[0]["path"], "changed.rs");
+    assert_eq!(parsed[3]["status"], "deleted");
+
+    super::write_report(&report, &report_file, super::ReportFormat::Csv).unwrap();
+    let csv = fs::read_to_string(&report_file).unwrap();
+    let rows: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        rows[0],
+        "path,kind,status,dirty_reason,source,hash,size,cached_timestamp,checkout_timestamp,new_timestamp,renamed_from,error"
+    );
+    assert!(rows[3].starts_with("\"new,file.rs\",file,dirty,new,checkout,"));
+    assert_eq!(rows[4], "gone.rs,file,deleted,,,,,,,,,");
+}
+
+#[test]
+fn affected_packages() {
+    use std::fs;
+
+    let (temp_dir, source_dir, cache_dir) = fixture();
+    let write = |path: &str, contents: &str| {
+        let path = source_dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    };
+    write(
+        "Cargo.toml",
+        "[workspace]\nmembers = [\"base\", \"mid\", \"top\", \"other\"]\nresolver = \"2\"\n",
+    );
+    let manifest = |name: &str, deps: &str| {
+        format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n{deps}")
+    };
+    write("base/Cargo.toml", &manifest("base", ""));
+    write(
+        "mid/Cargo.toml",
+        &manifest("mid", "[dependencies]\nbase = { path = \"../base\" }\n"),
+    );
+    write(
+        "top/Cargo.toml",
+        &manifest("top", "[dev-dependencies]\nmid = { path = \"../mid\" }\n"),
+    );
+    write("other/Cargo.toml", &manifest("other", ""));
+    for name in ["base", "mid", "top", "other"] {
+        write(&format!("{name}/src/lib.rs"), "// lib");
+    }
+    write("base/src/util.rs", "// util");
+    write("README.md", "# readme");
+    super::sync(source_dir.clone(), cache_dir.clone());
+
+    let report = super::sync(source_dir.clone(), cache_dir.clone());
+    let affected = super::affected_packages(&source_dir, &report).unwrap();
+    assert_eq!(affected, super::AffectedPackages::default());
+
+    write("mid/src/lib.rs", "// changed");
+    write("README.md", "# changed");
+    let report = super::sync(source_dir.clone(), cache_dir.clone());
+    let affected = super::affected_packages(&source_dir, &report).unwrap();
+    assert_eq!(affected.direct, ["mid"]);
+    assert_eq!(affected.dependents, ["top"]);
+    assert_eq!(affected.unowned.len(), 1);
+    assert_eq!(affected.unowned[0].to_string(), "README.md");
+
+    fs::remove_file(source_dir.join("base/src/util.rs")).unwrap();
+    let report = super::sync(source_dir.clone(), cache_dir);
+    let affected = super::affected_packages(&source_dir, &report).unwrap();
+    assert_eq!(affected.direct, ["base"]);
+    assert_eq!(affected.all(), ["base", "mid", "top"]);
+
+    let json_file = Utf8PathBuf::from_path_buf(temp_dir.path().join("affected.json")).unwrap();
+    affected.write_json(&json_file).unwrap();
+    let json = fs::read_to_string(&json_file).unwrap();
+    assert!(json.contains("\"dependents\": [\n    \"mid\",\n    \"top\"\n  ]"));
+}
+
+#[test]
+fn github_step_summary() {
+    use std::fs;
+
+    let (_temp_dir, source_dir, cache_dir) = fixture();
+    fs::create_dir_all(source_dir.join("src/bin")).unwrap();
+    fs::create_dir_all(source_dir.join("docs")).unwrap();
+    for name in [
+        "src/a.rs",
+        "src/b.rs",
+        "src/bin/c.rs",
+        "README.md",
+        "gone.md",
+        "docs/gone.md",
+    ] {
+        fs::write(source_dir.join(name), "// before").unwrap();
+    }
+    let store = super::FsStore::new(cache_dir.clone());
+    let report = super::sync(source_dir.clone(), cache_dir.clone());
+    let summary = super::github::step_summary(&report, &store);
+    assert!(summary.contains("| 0 | 0 | 6 | 0 |"));
+    assert!(summary.contains(&format!("No valid cache in `{cache_dir}`")));
+
+    fs::write(source_dir.join("src/a.rs"), "// after").unwrap();
+    fs::write(source_dir.join("src/b.rs"), "// after").unwrap();
+    fs::write(source_dir.join("src/bin/d.rs"), "// new").unwrap();
+    fs::remove_file(source_dir.join("gone.md")).unwrap();
+    fs::remove_dir_all(source_dir.join("docs")).unwrap();
+    fs::write(cache_dir.join("broken.db"), "not a cache").unwrap();
+    let old_cache = super::read_cache_from(&store, super::DEFAULT_CACHE_NAME).unwrap();
+    let options = super::SyncOptions {
+        read_from: vec!["broken".to_owned(), super::DEFAULT_CACHE_NAME.to_owned()],
+        ..Default::default()
+    };
+    let report = super::sync_with_options(source_dir, cache_dir.clone(), &options);
+    assert_eq!(report.cache_problems.len(), 1);
+    assert!(report.cache_problems[0].starts_with("Cache broken: "));
+    let summary = super::github::step_summary(&report, &store);
+    assert!(summary.starts_with("### ⏱️ timelord\n\n| Fresh | Dirty | New | Deleted |\n"));
+    // The deleted `docs` directory doesn't count
+    assert!(summary.contains("| 2 | 2 | 1 | 2 |"));
+    assert!(summary.contains(&format!(
+        "Restored from cache `timelord` in `{cache_dir}`, crawled "
+    )));
+    assert!(summary.contains(&format!(" ago on `{}`.", old_cache.hostname)));
+    assert!(summary.ends_with("|---|---:|\n| `src` | 2 |\n| `src/bin` | 1 |\n\n"));
+}
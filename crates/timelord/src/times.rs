@@ -0,0 +1,132 @@
+//! Setting timestamps by path, without opening files
+
+use camino::Utf8Path;
+use std::time::{Duration, SystemTime};
+
+/// Sets the mtime of whatever is at `path`, leaving its atime alone. Symlinks
+/// aren't followed: their own mtime is set. Since the file is never opened,
+/// this works on files we can't read or write, as long as we own them.
+pub(crate) fn set_mtime(path: &Utf8Path, mtime: SystemTime) -> std::io::Result<()> {
+    set_times(path, mtime, None)
+}
+
+/// Like [`set_mtime`], but also sets the atime if one is given.
+#[cfg(unix)]
+pub(crate) fn set_times(
+    path: &Utf8Path,
+    mtime: SystemTime,
+    atime: Option<SystemTime>,
+) -> std::io::Result<()> {
+    use std::ffi::CString;
+
+    let path = CString::new(path.as_str())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut times = [to_timespec(atime.unwrap_or(mtime)), to_timespec(mtime)];
+    if atime.is_none() {
+        times[0].tv_nsec = libc::UTIME_OMIT;
+    }
+
+    // SAFETY: `path` is a valid NUL-terminated string and `times` holds two timespecs
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_times(
+    path: &Utf8Path,
+    mtime: SystemTime,
+    atime: Option<SystemTime>,
+) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_symlink() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "setting symlink timestamps is only supported on unix",
+        ));
+    }
+    let mut times = std::fs::FileTimes::new().set_modified(mtime);
+    if let Some(atime) = atime {
+        times = times.set_accessed(atime);
+    }
+    std::fs::File::open(path)?.set_times(times)
+}
+
+#[cfg(unix)]
+fn to_timespec(time: SystemTime) -> libc::timespec {
+    let (secs, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos() as i64),
+        Err(e) => {
+            // Before the epoch: tv_nsec must stay positive
+            let d = e.duration();
+            let (secs, nanos) = (-(d.as_secs() as i64), -(d.subsec_nanos() as i64));
+            if nanos < 0 {
+                (secs - 1, nanos + 1_000_000_000)
+            } else {
+                (secs, nanos)
+            }
+        }
+    };
+
+    // SAFETY: timespec is plain old data, some targets have padding fields
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    ts.tv_sec = secs as libc::time_t;
+    ts.tv_nsec = nanos as _;
+    ts
+}
+
+/// Granularities we know filesystems to store mtimes with, finest first
+pub(crate) const GRANULARITIES: [Duration; 7] = [
+    Duration::from_nanos(1),
+    Duration::from_micros(1),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+];
+
+/// Finds out how precisely the filesystem holding `dir` stores mtimes, by
+/// setting an awkward mtime on a scratch file and reading it back.
+pub(crate) fn probe_granularity(dir: &Utf8Path) -> std::io::Result<Duration> {
+    let probe = dir.join(format!(".timelord-probe-{}", std::process::id()));
+    std::fs::File::create_new(&probe)?;
+    let result = (|| {
+        // An odd number of seconds, and every sub-second digit in use
+        let probe_time = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_001, 123_456_789);
+        set_mtime(&probe, probe_time)?;
+        let read_back = std::fs::symlink_metadata(&probe)?.modified()?;
+        Ok(granularity_of(probe_time, read_back))
+    })();
+    let _ = std::fs::remove_file(&probe);
+    result
+}
+
+/// Guesses the granularity that turned `set` into `read_back`, allowing for
+/// filesystems that round up instead of truncating.
+pub(crate) fn granularity_of(set: SystemTime, read_back: SystemTime) -> Duration {
+    let since_epoch = |t: SystemTime| {
+        t.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    };
+    let (set, read_back) = (since_epoch(set), since_epoch(read_back));
+    for granularity in GRANULARITIES {
+        let step = granularity.as_nanos();
+        let truncated = set / step * step;
+        if read_back == truncated || read_back == truncated + step {
+            return granularity;
+        }
+    }
+    // Not a granularity we know of, go with however far off it was
+    Duration::from_nanos(set.abs_diff(read_back) as u64)
+}